@@ -1,28 +1,18 @@
 use primoria::drivers::vga as vga_driver;
-use primoria::kernel::{thread_id, ticks};
+use primoria::kernel::channel::Sender;
+use primoria::kernel::{sleep, thread_id, user};
 use vga::colors::Color16;
 
-pub fn simple_counter_1() {
-    simple_counter_args(79, Color16::Green, false);
-}
-pub fn simple_counter_2() {
-    simple_counter_args(69, Color16::Blue, true);
-}
-
-fn simple_counter_args(base_col: usize, color: Color16, wait: bool) {
+pub fn simple_counter(base_col: usize, color: Color16, wait: bool) {
     let mut n: u64 = 0;
     let mut digits = [0u8; 16];
     let mut prev_digits = [0u8; 16];
     let digit_chars = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
 
-    let mut prev = ticks();
     loop {
-        let mut now = ticks();
-        while wait && now - prev < 1 {
-            now = ticks();
-            core::hint::spin_loop();
+        if wait {
+            sleep(1);
         }
-        prev = now;
 
         let mut digit_count = 0;
         let mut i = n;
@@ -46,11 +36,39 @@ fn simple_counter_args(base_col: usize, color: Color16, wait: bool) {
 pub fn simple_loop() {
     let mut i: u64 = 0;
     loop {
-        let cur = ticks();
         primoria::sprintln!("thread {} (i = {})", thread_id(), i);
-        while ticks() - cur < 10 {
-            core::hint::spin_loop();
-            i += 1;
+        sleep(10);
+        i += 1;
+    }
+}
+
+/// sends the remaining count every 20 ticks
+pub fn countdown(from: usize, progress: Sender<usize>) {
+    for i in (0..from).rev() {
+        sleep(20);
+        if progress.send(i).is_err() {
+            primoria::sprintln!("thread {}: nobody listens to the countdown", thread_id());
+            return;
         }
     }
 }
+
+/// runs in ring 3: sums the integers up to 100, slowly, and exits with the result
+#[link_section = "user_text"]
+pub extern "C" fn user_sum() {
+    let mut sum: usize = 0;
+    let mut i: usize = 1;
+    while i <= 100 {
+        sum = sum.wrapping_add(i);
+        i = i.wrapping_add(1);
+        user::sleep(1);
+    }
+    user::exit(sum);
+}
+
+/// runs in ring 3 and tries to draw on the screen, which only the kernel may do
+#[link_section = "user_text"]
+pub extern "C" fn user_scribble() {
+    let vga_text_buffer = 0xb8000 as *mut u8;
+    unsafe { *vga_text_buffer = b'!' };
+}
@@ -157,6 +157,15 @@ impl SerialPort {
             port_byte_in(self.port_data())
         }
     }
+
+    /// Receives a byte on the serial port, None if none was received.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+            Some(unsafe { port_byte_in(self.port_data()) })
+        } else {
+            None
+        }
+    }
 }
 
 impl fmt::Write for SerialPort {
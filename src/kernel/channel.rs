@@ -0,0 +1,261 @@
+//! Bounded message queues between threads.
+//!
+//! `send` blocks while the queue is full and `recv` while it is empty,
+//! the waiting threads sleep on the `kernel::sync` primitives.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::sync::{CondVar, Mutex};
+use super::{wait_on, wake};
+
+/// creates a channel holding at most `capacity` messages (at least 1)
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity.max(1)),
+            capacity: capacity.max(1),
+            senders: 1,
+            receiver_alive: true,
+            selectors: Vec::new(),
+        }),
+        not_empty: CondVar::new(),
+        not_full: CondVar::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_empty: CondVar,
+    not_full: CondVar,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    // `select` calls waiting on this channel among others
+    selectors: Vec<Arc<Signal>>,
+}
+
+impl<T> State<T> {
+    fn notify_selectors(&self) {
+        for signal in self.selectors.iter() {
+            signal.notify();
+        }
+    }
+}
+
+/// wakes up a `select` waiting on several channels
+struct Signal {
+    word: AtomicU32,
+}
+
+impl Signal {
+    fn notify(&self) {
+        self.word.fetch_add(1, Ordering::Release);
+        wake(&self.word, usize::MAX);
+    }
+}
+
+/// the receiver was dropped, the message is given back
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+/// the channel is empty and all the senders were dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// waits for a free slot if the channel is full
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let state = self.shared.state.lock();
+        let mut state = self.shared.not_full.wait_while(state, |state| {
+            state.receiver_alive && state.queue.len() >= state.capacity
+        });
+        if !state.receiver_alive {
+            return Err(SendError(value));
+        }
+        state.queue.push_back(value);
+        state.notify_selectors();
+        drop(state);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state.lock();
+        if !state.receiver_alive {
+            return Err(TrySendError::Disconnected(value));
+        }
+        if state.queue.len() >= state.capacity {
+            return Err(TrySendError::Full(value));
+        }
+        state.queue.push_back(value);
+        state.notify_selectors();
+        drop(state);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.notify_selectors();
+            drop(state);
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// waits for a message if the channel is empty
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let state = self.shared.state.lock();
+        let mut state = self
+            .shared
+            .not_empty
+            .wait_while(state, |state| state.senders > 0 && state.queue.is_empty());
+        let value = state.queue.pop_front().ok_or(RecvError)?;
+        drop(state);
+        self.shared.not_full.notify_one();
+        Ok(value)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock();
+        match state.queue.pop_front() {
+            Some(value) => {
+                drop(state);
+                self.shared.not_full.notify_one();
+                Ok(value)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn add_selector(&self, signal: &Arc<Signal>) {
+        self.shared.state.lock().selectors.push(signal.clone());
+    }
+
+    fn remove_selector(&self, signal: &Arc<Signal>) {
+        self.shared
+            .state
+            .lock()
+            .selectors
+            .retain(|selector| !Arc::ptr_eq(selector, signal));
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().receiver_alive = false;
+        self.shared.not_full.notify_all();
+    }
+}
+
+/// waits for a message on any of the receivers,
+/// returns the index of the receiver along with what `recv` would have returned on it
+///
+/// the receivers are checked in order, so the first ones win when several have messages
+pub fn select<T>(receivers: &[&Receiver<T>]) -> (usize, Result<T, RecvError>) {
+    let signal = Arc::new(Signal {
+        word: AtomicU32::new(0),
+    });
+    // registered before the first check, a message sent after it bumps the signal
+    for receiver in receivers {
+        receiver.add_selector(&signal);
+    }
+
+    let ret = 'wait: loop {
+        let seen = signal.word.load(Ordering::Acquire);
+        for (index, receiver) in receivers.iter().enumerate() {
+            match receiver.try_recv() {
+                Ok(value) => break 'wait (index, Ok(value)),
+                Err(TryRecvError::Disconnected) => break 'wait (index, Err(RecvError)),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        wait_on(&signal.word, seen);
+    };
+
+    for receiver in receivers {
+        receiver.remove_selector(&signal);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{join, launch_with};
+
+    #[test_case]
+    fn test_channel_blocks_when_full() {
+        let (sender, receiver) = channel(2);
+        let id = launch_with(move || {
+            for i in 0..20 {
+                sender.send(i).unwrap();
+            }
+        })
+        .unwrap();
+        for i in 0..20 {
+            assert_eq!(receiver.recv(), Ok(i));
+        }
+        // the producer finished and dropped its sender
+        assert_eq!(receiver.recv(), Err(RecvError));
+        join(id);
+    }
+
+    #[test_case]
+    fn test_select_waits_on_all_receivers() {
+        let (_sender1, receiver1) = channel::<usize>(1);
+        let (sender2, receiver2) = channel(1);
+        let id = launch_with(move || sender2.send(42).unwrap()).unwrap();
+        assert_eq!(select(&[&receiver1, &receiver2]), (1, Ok(42)));
+        join(id);
+        assert_eq!(receiver1.try_recv(), Err(TryRecvError::Empty));
+    }
+}
@@ -0,0 +1,559 @@
+//! Loading ELF64 programs into processes.
+//!
+//! A program is a static x86_64 executable built apart from the kernel, and embedded
+//! with `include_bytes!` until there is a filesystem. A position independent one
+//! (ET_DYN, e.g. linked with `-static-pie`) is loaded at the start of the program area
+//! of the process region, its R_X86_64_RELATIVE relocations applied. Any other one
+//! (ET_EXEC) must be linked inside that area, see `process::PROGRAMS_OFFSET`. There is
+//! no dynamic linking.
+//!
+//! The main thread starts at the entry point on the stack the System V ABI describes:
+//! argc at the stack pointer, then argv, envp and the auxiliary vector, with the strings
+//! above them. It must exit rather than return.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::process::{self, Pid, PROGRAMS_OFFSET, REGION_SIZE};
+use super::syscall::{self, SyscallResult};
+use super::{Errno, StackFrame};
+use crate::system::paging::{self, AddressSpace};
+
+const PAGE_SIZE: usize = 4096;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// not a 64-bit little endian x86_64 executable, or one that needs an interpreter
+    Unsupported,
+    /// a header or a segment goes past the end of the image
+    Truncated,
+    /// a segment or the entry point is outside the program area
+    OutOfProgramArea,
+    UnsupportedRelocation(u32),
+    /// the arguments and the environment don't fit in the top page of the stack
+    ArgumentsTooLong,
+    TooManyThreads,
+    OutOfMemory,
+}
+
+/// loads the program `image` in a new process, whose main thread gets `args` and `env`
+///
+/// by convention `args[0]` is the name of the program, the process is named after it
+pub fn spawn_elf(image: &[u8], args: &[&str], env: &[&str]) -> Result<Pid, ElfError> {
+    // the syscall only tells that the image is invalid, not why
+    Elf::parse(image)?;
+    syscall::int::spawn_elf(
+        image.as_ptr(),
+        image.len(),
+        args.as_ptr(),
+        args.len(),
+        env.as_ptr(),
+        env.len(),
+    )
+    .map_err(|errno| match errno {
+        Errno::TooManyThreads => ElfError::TooManyThreads,
+        Errno::OutOfMemory => ElfError::OutOfMemory,
+        Errno::ArgumentsTooLong => ElfError::ArgumentsTooLong,
+        errno => unreachable!("spawn_elf: {:?}", errno),
+    })
+}
+
+/// the SpawnElf syscall
+pub(super) unsafe fn spawn(
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+    stack_frame: &StackFrame,
+) -> SyscallResult {
+    let elf = Elf::parse(image).map_err(|_| Errno::InvalidExecutable)?;
+    super::check_thread_limit()?;
+    let mut address_space = AddressSpace::new().ok_or(Errno::OutOfMemory)?;
+    elf.load(&mut address_space)?;
+
+    let pid = process::new_process(args.first().copied().unwrap_or("?"), address_space);
+    let main_thread = process::map_stack(pid).and_then(|(stack_end, top_page)| {
+        let stack_pointer = elf.write_initial_stack(stack_end, top_page, args, env)?;
+        process::start_thread(pid, elf.entry as usize, stack_pointer, stack_frame)
+    });
+    process::started(pid, main_thread)
+}
+
+/// a PT_LOAD segment, at its final address
+struct Segment {
+    offset: usize,
+    addr: u64,
+    file_size: usize,
+    mem_size: u64,
+    writable: bool,
+    executable: bool,
+}
+
+impl Segment {
+    fn contains(&self, addr: u64, size: u64) -> bool {
+        addr >= self.addr && addr.saturating_add(size) <= self.addr + self.mem_size
+    }
+}
+
+/// a parsed and checked program, with the addresses it gets in the program area
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    segments: Vec<Segment>,
+    // (address, value) of the words to patch after loading
+    relocations: Vec<(u64, u64)>,
+    // where the program headers end up, if a segment loads them
+    phdr: Option<u64>,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.get(..4) != Some(b"\x7fELF".as_slice()) {
+            return Err(ElfError::NotElf);
+        }
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        // 64-bit, little endian
+        if image[4] != 2 || image[5] != 1 || read_u16(image, 18)? != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+
+        let area_start = paging::process_region().as_u64() + PROGRAMS_OFFSET;
+        let area_end = paging::process_region().as_u64() + REGION_SIZE;
+        let base = match read_u16(image, 16)? {
+            ET_EXEC => 0,
+            ET_DYN => area_start,
+            _ => return Err(ElfError::Unsupported),
+        };
+
+        let phoff = read_u64(image, 32)? as usize;
+        let phentsize = read_u16(image, 54)? as usize;
+        let phnum = read_u16(image, 56)? as usize;
+        if phentsize < PHDR_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let mut segments = Vec::new();
+        // file offset and size of the dynamic section
+        let mut dynamic = None;
+        for index in 0..phnum {
+            let ph = index
+                .checked_mul(phentsize)
+                .and_then(|offset| offset.checked_add(phoff))
+                .ok_or(ElfError::Truncated)?;
+            match read_u32(image, ph)? {
+                PT_LOAD => {
+                    let flags = read_u32(image, ph + 4)?;
+                    let segment = Segment {
+                        offset: read_u64(image, ph + 8)? as usize,
+                        addr: base
+                            .checked_add(read_u64(image, ph + 16)?)
+                            .ok_or(ElfError::OutOfProgramArea)?,
+                        file_size: read_u64(image, ph + 32)? as usize,
+                        mem_size: read_u64(image, ph + 40)?,
+                        writable: flags & PF_W != 0,
+                        executable: flags & PF_X != 0,
+                    };
+                    if bytes(image, segment.offset, segment.file_size).is_err()
+                        || segment.file_size as u64 > segment.mem_size
+                    {
+                        return Err(ElfError::Truncated);
+                    }
+                    match segment.addr.checked_add(segment.mem_size) {
+                        Some(end) if segment.addr >= area_start && end <= area_end => {}
+                        _ => return Err(ElfError::OutOfProgramArea),
+                    }
+                    segments.push(segment);
+                }
+                PT_DYNAMIC => {
+                    dynamic = Some((
+                        read_u64(image, ph + 8)? as usize,
+                        read_u64(image, ph + 32)? as usize,
+                    ))
+                }
+                PT_INTERP => return Err(ElfError::Unsupported),
+                _ => {}
+            }
+        }
+
+        let entry = base.wrapping_add(read_u64(image, 24)?);
+        if !segments
+            .iter()
+            .any(|segment| segment.executable && segment.contains(entry, 1))
+        {
+            return Err(ElfError::OutOfProgramArea);
+        }
+
+        let phdr_size = (phnum * phentsize) as u64;
+        let phdr = segments
+            .iter()
+            .find(|segment| {
+                let start = segment.offset as u64;
+                phoff as u64 >= start
+                    && phoff as u64 + phdr_size <= start + segment.file_size as u64
+            })
+            .map(|segment| segment.addr + (phoff - segment.offset) as u64);
+
+        let relocations = match dynamic {
+            Some((offset, size)) => relocations(image, base, &segments, offset, size)?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            image,
+            entry,
+            segments,
+            relocations,
+            phdr,
+            phnum,
+        })
+    }
+
+    /// maps the segments in `space`, copies them and applies the relocations
+    ///
+    /// safety: `space` must be a fresh address space
+    unsafe fn load(&self, space: &mut AddressSpace) -> Result<(), Errno> {
+        for segment in self.segments.iter().filter(|segment| segment.mem_size > 0) {
+            let mut flags = PageTableFlags::USER_ACCESSIBLE;
+            if segment.writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            let pages = Page::range_inclusive(
+                Page::containing_address(VirtAddr::new(segment.addr)),
+                Page::containing_address(VirtAddr::new(segment.addr + segment.mem_size - 1)),
+            );
+            for page in pages {
+                // the segments may share their first and last pages
+                if space.kernel_addr(page).is_some() {
+                    space.add_flags(page, flags);
+                } else {
+                    space.map_zeroed(page, flags).ok_or(Errno::OutOfMemory)?;
+                }
+            }
+            let data = &self.image[segment.offset..segment.offset + segment.file_size];
+            copy_to(space, segment.addr, data);
+        }
+        for &(addr, value) in &self.relocations {
+            copy_to(space, addr, &value.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// writes argc, argv, envp and the auxiliary vector in the top page of the stack,
+    /// the strings above them, and returns the stack pointer
+    unsafe fn write_initial_stack(
+        &self,
+        stack_end: usize,
+        top_page: *mut u8,
+        args: &[&str],
+        env: &[&str],
+    ) -> Result<usize, Errno> {
+        let page_start = stack_end - PAGE_SIZE;
+        let mut page = vec![0u8; PAGE_SIZE];
+        // offset in the page of the lowest byte written so far
+        let mut top = PAGE_SIZE;
+
+        let mut strings = Vec::with_capacity(args.len() + env.len());
+        for string in args.iter().chain(env) {
+            // with the terminating 0, already there
+            top = top
+                .checked_sub(string.len() + 1)
+                .ok_or(Errno::ArgumentsTooLong)?;
+            page[top..top + string.len()].copy_from_slice(string.as_bytes());
+            strings.push(page_start + top);
+        }
+        let (argv, envp) = strings.split_at(args.len());
+
+        let mut words = vec![args.len()];
+        words.extend_from_slice(argv);
+        words.push(0);
+        words.extend_from_slice(envp);
+        words.push(0);
+        if let Some(phdr) = self.phdr {
+            words.extend([AT_PHDR, phdr as usize, AT_PHENT, PHDR_SIZE]);
+            words.extend([AT_PHNUM, self.phnum]);
+        }
+        words.extend([AT_PAGESZ, PAGE_SIZE, AT_ENTRY, self.entry as usize]);
+        words.extend([AT_NULL, 0]);
+
+        // aligned on 16 bytes, at argc
+        let start = top
+            .checked_sub(words.len() * size_of::<usize>())
+            .ok_or(Errno::ArgumentsTooLong)?
+            & !0xf;
+        for (index, word) in words.iter().enumerate() {
+            let offset = start + index * size_of::<usize>();
+            page[offset..offset + size_of::<usize>()].copy_from_slice(&word.to_le_bytes());
+        }
+        core::ptr::copy_nonoverlapping(page.as_ptr(), top_page, PAGE_SIZE);
+        Ok(page_start + start)
+    }
+}
+
+/// the R_X86_64_RELATIVE relocations of the dynamic section, as (address, value)
+fn relocations(
+    image: &[u8],
+    base: u64,
+    segments: &[Segment],
+    dynamic_offset: usize,
+    dynamic_size: usize,
+) -> Result<Vec<(u64, u64)>, ElfError> {
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry = RELA_SIZE;
+    for index in 0..dynamic_size / DYN_SIZE {
+        let entry = dynamic_offset + index * DYN_SIZE;
+        let value = read_u64(image, entry + 8)?;
+        match read_u64(image, entry)? {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value as usize,
+            DT_RELAENT => rela_entry = value as usize,
+            _ => {}
+        }
+    }
+    let rela = match rela {
+        Some(rela) => rela,
+        None => return Ok(Vec::new()),
+    };
+    if rela_entry < RELA_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+    // the table is read from the file, find where its address is loaded from
+    let offset = segments
+        .iter()
+        .find(|segment| segment.contains(base + rela, rela_size as u64))
+        .map(|segment| segment.offset + (base + rela - segment.addr) as usize)
+        .ok_or(ElfError::Truncated)?;
+
+    let mut relocations = Vec::new();
+    for index in 0..rela_size / rela_entry {
+        let entry = offset + index * rela_entry;
+        let addr = base.wrapping_add(read_u64(image, entry)?);
+        let kind = read_u64(image, entry + 8)? as u32;
+        let addend = read_u64(image, entry + 16)?;
+        match kind {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                if !segments.iter().any(|segment| segment.contains(addr, 8)) {
+                    return Err(ElfError::OutOfProgramArea);
+                }
+                relocations.push((addr, base.wrapping_add(addend)));
+            }
+            kind => return Err(ElfError::UnsupportedRelocation(kind)),
+        }
+    }
+    Ok(relocations)
+}
+
+/// copies `data` at `addr` in `space`, whose pages must be mapped
+unsafe fn copy_to(space: &AddressSpace, addr: u64, data: &[u8]) {
+    let mut done = 0;
+    while done < data.len() {
+        let addr = VirtAddr::new(addr + done as u64);
+        let page = Page::containing_address(addr);
+        let offset = (addr - page.start_address()) as usize;
+        let size = (PAGE_SIZE - offset).min(data.len() - done);
+        let dest = space.kernel_addr(page).expect("copy_to: page not mapped");
+        core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dest.add(offset), size);
+        done += size;
+    }
+}
+
+fn bytes(image: &[u8], offset: usize, size: usize) -> Result<&[u8], ElfError> {
+    offset
+        .checked_add(size)
+        .and_then(|end| image.get(offset..end))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(
+        bytes(image, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(
+        bytes(image, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(
+        bytes(image, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::syscall::Syscall;
+    use crate::kernel::{join, launch_user, user, wait_process, FAULT_EXIT_CODE, STATE};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    const CODE: usize = 0x100;
+    const DATA: usize = 0x180;
+    const RELA: usize = 0x1a0;
+    const DYNAMIC: usize = 0x1c0;
+    const SIZE: usize = 0x200;
+
+    /// a position independent program running `code`, followed by an exit with rdi,
+    /// and whose data starts with a relocated pointer to the byte 42 right after it
+    fn program(code: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(16, &ET_DYN.to_le_bytes());
+        put(18, &EM_X86_64.to_le_bytes());
+        put(24, &(CODE as u64).to_le_bytes());
+        put(32, &(EHDR_SIZE as u64).to_le_bytes());
+        put(52, &(EHDR_SIZE as u16).to_le_bytes());
+        put(54, &(PHDR_SIZE as u16).to_le_bytes());
+        put(56, &2u16.to_le_bytes());
+
+        let headers = [
+            (PT_LOAD, 7, 0, SIZE),
+            (PT_DYNAMIC, 6, DYNAMIC, 4 * DYN_SIZE),
+        ];
+        for (index, (kind, flags, offset, size)) in headers.into_iter().enumerate() {
+            let ph = EHDR_SIZE + index * PHDR_SIZE;
+            put(ph, &kind.to_le_bytes());
+            put(ph + 4, &(flags as u32).to_le_bytes());
+            // offset, virtual and physical addresses
+            for field in [8, 16, 24] {
+                put(ph + field, &(offset as u64).to_le_bytes());
+            }
+            put(ph + 32, &(size as u64).to_le_bytes());
+            put(ph + 40, &(size as u64).to_le_bytes());
+        }
+
+        let exit = [
+            &[0xb8][..],
+            &(Syscall::Exit as u32).to_le_bytes(),
+            &[0x0f, 0x05],
+        ];
+        put(CODE, &[code, &exit.concat()].concat());
+
+        put(DATA + 8, &[42]);
+        put(RELA, &(DATA as u64).to_le_bytes());
+        put(RELA + 8, &(R_X86_64_RELATIVE as u64).to_le_bytes());
+        put(RELA + 16, &(DATA as u64 + 8).to_le_bytes());
+        let dynamic = [
+            (DT_RELA, RELA),
+            (DT_RELASZ, RELA_SIZE),
+            (DT_RELAENT, RELA_SIZE),
+        ];
+        for (index, (tag, value)) in dynamic.into_iter().enumerate() {
+            put(DYNAMIC + index * DYN_SIZE, &tag.to_le_bytes());
+            put(
+                DYNAMIC + index * DYN_SIZE + 8,
+                &(value as u64).to_le_bytes(),
+            );
+        }
+        image
+    }
+
+    #[test_case]
+    fn test_program_gets_argc() {
+        // mov rdi, [rsp]
+        let image = program(&[0x48, 0x8b, 0x3c, 0x24]);
+        let pid = spawn_elf(&image, &["prog", "a", "b"], &["PATH=/"]).unwrap();
+        assert_eq!(wait_process(pid), 3);
+    }
+
+    #[test_case]
+    fn test_program_gets_argv() {
+        // mov rax, [rsp + 16]; movzx edi, byte ptr [rax]
+        let image = program(&[0x48, 0x8b, 0x44, 0x24, 0x10, 0x0f, 0xb6, 0x38]);
+        let pid = spawn_elf(&image, &["prog", "x"], &[]).unwrap();
+        assert_eq!(wait_process(pid), b'x' as usize);
+    }
+
+    #[test_case]
+    fn test_program_is_relocated() {
+        // mov rax, [rip + DATA]; movzx edi, byte ptr [rax]
+        let disp = (DATA - (CODE + 7)) as u32;
+        let code = [
+            &[0x48, 0x8b, 0x05][..],
+            &disp.to_le_bytes(),
+            &[0x0f, 0xb6, 0x38],
+        ];
+        let pid = spawn_elf(&program(&code.concat()), &["prog"], &[]).unwrap();
+        assert_eq!(wait_process(pid), 42);
+    }
+
+    #[test_case]
+    fn test_invalid_programs_are_rejected() {
+        assert_eq!(spawn_elf(b"#!/bin/sh", &[], &[]), Err(ElfError::NotElf));
+
+        let image = program(&[]);
+        assert_eq!(
+            spawn_elf(&image[..SIZE / 2], &[], &[]),
+            Err(ElfError::Truncated)
+        );
+
+        // linked at 0, outside the program area
+        let mut image = program(&[]);
+        image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        assert_eq!(spawn_elf(&image, &[], &[]), Err(ElfError::OutOfProgramArea));
+    }
+
+    #[link_section = "user_text"]
+    extern "C" fn user_nap() {
+        user::sleep(20);
+        user::exit(0);
+    }
+
+    #[test_case]
+    fn test_program_cannot_touch_user_stack_of_thread() {
+        let victim = launch_user(user_nap).unwrap();
+        let top = without_interrupts(|| unsafe {
+            STATE.thread(victim).user_stack.as_ref().unwrap().end() - 8
+        });
+        // mov rax, top; mov rdi, [rax]
+        let code = [
+            &[0x48, 0xb8][..],
+            &(top as u64).to_le_bytes(),
+            &[0x48, 0x8b, 0x38],
+        ];
+        let pid = spawn_elf(&program(&code.concat()), &["prog"], &[]).unwrap();
+        assert_eq!(wait_process(pid), FAULT_EXIT_CODE);
+        assert_eq!(join(victim), 0);
+    }
+}
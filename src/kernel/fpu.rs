@@ -0,0 +1,226 @@
+//! FPU/SSE registers of the threads.
+//!
+//! The registers hold the state of one thread at a time, the owner. Whenever another
+//! thread runs, CR0.TS is set: its first x87/SSE instruction raises #NM (device not
+//! available), and the handler saves the registers of the owner before loading its own.
+//! A thread that does not use floating point never costs a save or a restore.
+//!
+//! The kernel entry points set TS as well, before any Rust code runs: on the hardware
+//! floating point target, the compiler may use the SSE registers in the kernel too, and
+//! the owner's registers have to be saved before they get clobbered.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// FXSAVE area
+const LEGACY_AREA_SIZE: usize = 512;
+const AREA_ALIGN: usize = 64;
+
+// offsets in the legacy area
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// x87 control word after FNINIT, all exceptions masked
+const DEFAULT_FCW: u16 = 0x037f;
+/// all SSE exceptions masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+// set once by `init`
+static mut USE_XSAVE: bool = false;
+static mut XSAVE_MASK: u64 = 0;
+static mut AREA_SIZE: usize = LEGACY_AREA_SIZE;
+/// the area of the owner, saved by `device_not_available_handler` before any Rust code
+/// runs, null while the kernel owns the registers
+pub(super) static mut OWNER_AREA: *mut u8 = core::ptr::null_mut();
+
+/// enables the FPU and SSE, and XSAVE if the cpu supports it
+///
+/// safety: must be called once, before the first `FpuState::new`
+pub unsafe fn init() {
+    Cr0::update(|flags| {
+        flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+        flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+    });
+    Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+    // CPUID.1:ECX.XSAVE
+    if __cpuid(1).ecx & (1 << 26) != 0 {
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+        let supported = XCr0Flags::from_bits_truncate(__cpuid_count(0xd, 0).eax as u64);
+        let enabled = supported & (XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX);
+        XCr0::write(enabled);
+
+        USE_XSAVE = true;
+        XSAVE_MASK = enabled.bits();
+        // size needed for the components enabled in XCR0
+        AREA_SIZE = __cpuid_count(0xd, 0).ebx as usize;
+    }
+    crate::sprintln!(
+        "fpu: {} ({} bytes per thread)",
+        if USE_XSAVE { "xsave" } else { "fxsave" },
+        AREA_SIZE
+    );
+}
+
+/// sets or clears CR0.TS, the next x87/SSE instruction raises #NM while it is set
+pub fn set_task_switched(switched: bool) {
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, switched));
+    }
+}
+
+/// saved FPU/SSE registers of a thread
+pub struct FpuState {
+    area: *mut u8,
+}
+
+// the area belongs to the state alone
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// the state after a reset: empty x87 stack, zeroed registers, exceptions masked
+    pub fn new() -> Self {
+        unsafe {
+            let area = alloc_zeroed(Self::layout());
+            assert!(!area.is_null(), "out of memory for an FPU state");
+            area.add(FCW_OFFSET).cast::<u16>().write(DEFAULT_FCW);
+            area.add(MXCSR_OFFSET).cast::<u32>().write(DEFAULT_MXCSR);
+            // the XSAVE header stays zeroed: every other component is in its initial state
+            Self { area }
+        }
+    }
+
+    fn layout() -> Layout {
+        unsafe { Layout::from_size_align(AREA_SIZE, AREA_ALIGN).unwrap() }
+    }
+
+    pub(super) fn area(&self) -> *mut u8 {
+        self.area
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area, Self::layout()) }
+    }
+}
+
+/// #NM, raised by the first FPU/SSE instruction while TS is set
+///
+/// naked: an `x86-interrupt` handler may save the SSE registers in its prologue,
+/// which would raise #NM again before TS is cleared. The registers are saved and
+/// loaded here too: on the hardware floating point target, `_switch_fpu` may use
+/// the SSE registers itself
+#[naked]
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    unsafe {
+        core::arch::asm!(
+            "clts",
+            // the registers `_switch_fpu` and XSAVE may clobber
+            "push rax",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            // the components to save and load, for XSAVE and XRSTOR
+            "mov eax, [rip + {mask}]",
+            "mov edx, [rip + {mask} + 4]",
+            "mov rdi, [rip + {owner_area}]",
+            "test rdi, rdi",
+            "jz 3f",
+            "cmp byte ptr [rip + {use_xsave}], 0",
+            "je 2f",
+            "xsave64 [rdi]",
+            "jmp 3f",
+            "2:",
+            "fxsave64 [rdi]",
+            "3:",
+            "call _switch_fpu",
+            "mov rdi, rax",
+            "test rdi, rdi",
+            "jz 5f",
+            "mov eax, [rip + {mask}]",
+            "mov edx, [rip + {mask} + 4]",
+            "cmp byte ptr [rip + {use_xsave}], 0",
+            "je 4f",
+            "xrstor64 [rdi]",
+            "jmp 5f",
+            "4:",
+            "fxrstor64 [rdi]",
+            "5:",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rax",
+            "iretq",
+            mask = sym XSAVE_MASK,
+            owner_area = sym OWNER_AREA,
+            use_xsave = sym USE_XSAVE,
+            options(noreturn),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kernel::syscall::Syscall;
+    use crate::kernel::{exit, join, launch_with};
+
+    /// loads `value` in xmm0 and st0, yields through `int 0x80` and reads them back,
+    /// the compiler gets no chance to touch the registers in between
+    fn registers_across_yield(value: u64) -> (u64, u64) {
+        let xmm: u64;
+        let st: u64;
+        unsafe {
+            core::arch::asm!(
+                // xmm0 may hold a value of the compiler on the hardware floating point target
+                "sub rsp, 16",
+                "movdqu [rsp], xmm0",
+                "push {value}",
+                "fild qword ptr [rsp]",
+                "movq xmm0, {value}",
+                "int 0x80",
+                "movq {xmm}, xmm0",
+                "fistp qword ptr [rsp]",
+                "pop {st}",
+                "movdqu xmm0, [rsp]",
+                "add rsp, 16",
+                value = in(reg) value,
+                xmm = out(reg) xmm,
+                st = out(reg) st,
+                inout("rax") Syscall::Yield as usize => _,
+            );
+        }
+        (xmm, st)
+    }
+
+    /// 1 if the registers kept `value` every time, the other threads load theirs in between
+    fn keeps_registers(value: u64) -> usize {
+        (0..20).all(|_| registers_across_yield(value) == (value, value)) as usize
+    }
+
+    #[test_case]
+    fn test_fpu_registers_are_per_thread() {
+        let ids = [0x1111_2222, 0x3333_4444, 0x5555_6666]
+            .map(|value| launch_with(move || exit(keeps_registers(value))).unwrap());
+        assert_eq!(keeps_registers(0x7777_8888), 1);
+        assert_eq!(ids.map(join), [1; 3]);
+    }
+}
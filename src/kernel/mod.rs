@@ -0,0 +1,1216 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::system::gdt;
+use crate::system::interrupts::{self, InterruptIndex};
+use crate::system::slab::{CacheBox, KmemCache};
+
+pub mod channel;
+pub mod elf;
+pub mod fpu;
+pub mod process;
+pub mod scheduler;
+pub mod stack;
+pub mod sync;
+pub mod syscall;
+pub mod user;
+
+pub use channel::channel;
+pub use elf::{spawn_elf, ElfError};
+pub use process::{spawn_process, wait_process, Pid};
+pub use syscall::Errno;
+pub use user::UserEntry;
+
+use fpu::FpuState;
+use scheduler::{FixedPriority, Priority, Scheduler, DEFAULT_PRIORITY, MAX_PRIORITY};
+use stack::Stack;
+use syscall::SyscallResult;
+
+const DEFAULT_STACK_SIZE: usize = 8 * 1024; // in bytes
+const MIN_STACK_SIZE: usize = 4 * 1024;
+const DEFAULT_MAX_THREADS: usize = 64;
+
+/// value passed to `exit` and returned by `join`
+pub type ExitCode = usize;
+
+/// larger exit codes are lowered to this one, `join` would read them as errors
+pub const MAX_EXIT_CODE: ExitCode = usize::MAX - syscall::MAX_ERRNO;
+/// exit code of the threads killed for overflowing their stack
+pub const STACK_OVERFLOW_EXIT_CODE: ExitCode = MAX_EXIT_CODE;
+/// exit code of the threads killed for a fault, e.g. ring 3 touching kernel memory
+pub const FAULT_EXIT_CODE: ExitCode = MAX_EXIT_CODE - 1;
+
+// global kernel state
+static mut STATE: State = State::DEFAULT;
+// the control blocks of the threads
+static THREADS: KmemCache<Thread> = KmemCache::new("thread");
+// the entries of the wait queues, one per waiting thread
+static WAIT_NODES: KmemCache<WaitNode> = KmemCache::new("wait node");
+
+pub fn init() {
+    unsafe {
+        fpu::init();
+        user::init();
+
+        let boot_thread = THREADS.alloc(Thread {
+            state: ThreadState::Running,
+            fpu: Some(FpuState::new()),
+            ..Thread::DEFAULT
+        });
+        STATE
+            .threads
+            .push(Some(boot_thread.ok().expect("no memory for thread 0")));
+
+        // interrupts enabled (IF) and the always-set bit 1
+        let mut idle = new_thread(
+            ThreadEntry::Kernel(Box::into_raw(Box::new(Box::new(idle) as ThreadMain))),
+            0x0202,
+            DEFAULT_STACK_SIZE,
+        )
+        .expect("no room for the idle thread stack");
+        idle.name = String::from("idle");
+        STATE.threads.push(Some(idle));
+        STATE.idle_thread = STATE.threads.len() - 1;
+
+        STATE.scheduler = Some(Box::new(FixedPriority::new()));
+    }
+}
+
+/// replaces the scheduling policy, the ready threads are moved to the new one
+pub fn set_scheduler(mut scheduler: Box<dyn Scheduler>) {
+    without_interrupts(|| unsafe {
+        for id in 0..STATE.threads.len() {
+            match STATE.get_thread(id) {
+                Some(thread) if thread.state == ThreadState::Ready && id != STATE.idle_thread => {
+                    scheduler.enqueue(id, thread.priority)
+                }
+                _ => {}
+            }
+        }
+        crate::sprintln!("scheduler: {}", scheduler.name());
+        STATE.scheduler = Some(scheduler);
+    });
+}
+
+/// sets the maximum number of threads alive (or finished but not joined) at once,
+/// `launch` fails past this limit
+pub fn set_max_threads(max: usize) {
+    without_interrupts(|| unsafe {
+        STATE.max_threads = max;
+    });
+}
+
+/// this function must be called exactly once
+pub unsafe fn start(main: fn()) -> ! {
+    let stack_pointer: usize;
+    core::arch::asm!(
+        "mov {sp}, rsp",
+        sp = out(reg) stack_pointer,
+    );
+    STATE.thread(0).stack_end = stack_pointer;
+
+    main();
+
+    exit(0);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchError {
+    TooManyThreads,
+    OutOfStackSpace,
+}
+
+/// what a thread runs, boxed again to pass it around as a thin pointer
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// read by the LaunchThread syscall, whose argument is its address
+#[repr(C)]
+pub struct LaunchSpec {
+    // owned by the new thread if the launch succeeds, null for a ring 3 thread
+    main: *mut ThreadMain,
+    // Some for a ring 3 thread
+    user_entry: Option<UserEntry>,
+    stack_size: usize,
+    priority: Priority,
+    name_ptr: *const u8,
+    name_len: usize,
+}
+
+/// launches a thread with the default settings, see `Builder`
+pub fn launch(thread: fn()) -> Result<usize, LaunchError> {
+    Builder::new().spawn(thread)
+}
+
+/// launches a thread running the closure `f`, with the default settings
+pub fn launch_with(f: impl FnOnce() + Send + 'static) -> Result<usize, LaunchError> {
+    Builder::new().spawn(f)
+}
+
+/// launches a thread running `entry` in ring 3, with the default settings, see `user`
+pub fn launch_user(entry: UserEntry) -> Result<usize, LaunchError> {
+    Builder::new().spawn_user(entry)
+}
+
+/// thread settings, e.g. `Builder::new().name("shell").priority(5).spawn(f)`
+pub struct Builder {
+    name: String,
+    stack_size: usize,
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            name: String::new(),
+            stack_size: DEFAULT_STACK_SIZE,
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+
+    /// shown in the kernel logs
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+
+    /// in bytes, at least 4 KiB, rounded up to whole pages
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn spawn(self, f: impl FnOnce() + Send + 'static) -> Result<usize, LaunchError> {
+        let main = Box::into_raw(Box::new(Box::new(f) as ThreadMain));
+        self.launch(main, None).inspect_err(|_| {
+            // the closure was not handed to any thread
+            drop(unsafe { Box::from_raw(main) });
+        })
+    }
+
+    /// the thread runs `entry` in ring 3, on a user accessible stack of `stack_size` bytes
+    pub fn spawn_user(self, entry: UserEntry) -> Result<usize, LaunchError> {
+        self.launch(core::ptr::null_mut(), Some(entry))
+    }
+
+    fn launch(
+        &self,
+        main: *mut ThreadMain,
+        user_entry: Option<UserEntry>,
+    ) -> Result<usize, LaunchError> {
+        let spec = LaunchSpec {
+            main,
+            user_entry,
+            stack_size: self.stack_size,
+            priority: self.priority,
+            name_ptr: self.name.as_ptr(),
+            name_len: self.name.len(),
+        };
+        syscall::int::launch_thread(&spec).map_err(|errno| match errno {
+            Errno::TooManyThreads => LaunchError::TooManyThreads,
+            Errno::OutOfMemory => LaunchError::OutOfStackSpace,
+            errno => unreachable!("launch: {:?}", errno),
+        })
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// terminates the calling thread
+///
+/// inlined, so that ring 3 code can use it
+#[inline(always)]
+pub fn exit(code: ExitCode) -> ! {
+    let _ = syscall::int::exit(code);
+    unreachable!("exit returned");
+}
+
+/// waits for the thread `id` to finish and returns its exit code
+///
+/// the calling thread does not get any cpu time until then
+pub fn join(id: usize) -> ExitCode {
+    match syscall::int::join(id) {
+        Ok(code) => code,
+        Err(errno) => panic!("join: thread {}: {:?}", id, errno),
+    }
+}
+
+/// returns the exit code of the thread `id` if it has finished, without blocking
+pub fn try_join(id: usize) -> Option<ExitCode> {
+    match syscall::int::try_join(id) {
+        Ok(code) => Some(code),
+        Err(Errno::WouldBlock) => None,
+        Err(errno) => panic!("try_join: thread {}: {:?}", id, errno),
+    }
+}
+
+/// gives the rest of the time slice to the other threads
+///
+/// inlined, so that ring 3 code can use it
+#[inline(always)]
+pub fn yield_now() {
+    let _ = syscall::int::yield_now();
+}
+
+/// takes the calling thread off the cpu for at least `ticks` timer ticks
+///
+/// inlined, so that ring 3 code can use it
+#[inline(always)]
+pub fn sleep(ticks: usize) {
+    let _ = syscall::int::sleep(ticks);
+}
+
+/// changes the priority of the thread `id`, see `scheduler::Priority`
+pub fn set_priority(id: usize, priority: Priority) -> Result<(), Errno> {
+    syscall::int::set_priority(id, priority).map(|_| ())
+}
+
+/// puts the calling thread to sleep in the wait queue of `word`,
+/// unless `word` no longer contains `expected`
+///
+/// the check and the sleep are atomic, so a `wake` after changing `word` can't be missed.
+/// may return spuriously, callers check their condition again
+pub fn wait_on(word: &AtomicU32, expected: u32) {
+    let _ = syscall::int::wait(word, expected);
+}
+
+/// wakes up to `count` threads waiting on `word`, returns how many were woken up
+pub fn wake(word: &AtomicU32, count: usize) -> usize {
+    syscall::int::wake(word, count).unwrap_or(0)
+}
+
+pub fn thread_id() -> usize {
+    unsafe { STATE.current_thread }
+}
+
+pub fn ticks() -> usize {
+    unsafe { STATE.ticks }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CpuRegs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+impl CpuRegs {
+    pub const DEFAULT: Self = Self {
+        rax: 0,
+        rbx: 0,
+        rcx: 0,
+        rdx: 0,
+        rsi: 0,
+        rdi: 0,
+        rbp: 0,
+        r8: 0,
+        r9: 0,
+        r10: 0,
+        r11: 0,
+        r12: 0,
+        r13: 0,
+        r14: 0,
+        r15: 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct StackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+impl StackFrame {
+    const DEFAULT: Self = Self {
+        instruction_pointer: 0,
+        code_segment: 0,
+        cpu_flags: 0,
+        stack_pointer: 0,
+        stack_segment: 0,
+    };
+}
+
+/// only valid on single-CPU
+struct State {
+    // indexed by thread id, None for the free ids
+    threads: Vec<Option<CacheBox<Thread>>>,
+    max_threads: usize,
+    // runs only when no other thread is runnable
+    idle_thread: usize,
+    // decides which ready thread runs next
+    scheduler: Option<Box<dyn Scheduler>>,
+    // threads blocked in `wait_on`, by address of the word
+    wait_queues: BTreeMap<usize, WaitQueue>,
+    // indexed by pid, None for the free pids
+    processes: Vec<Option<Box<process::Process>>>,
+    // thread whose state is in the FPU/SSE registers, see `fpu`
+    fpu_owner: Option<usize>,
+    // between `_enter_kernel` and `back_to_thread`
+    in_kernel: bool,
+    // threads[current_thread] means nothing,
+    // except between a call to `switch_stack_frame` and `back_to_thread`,
+    // where the cpu_regs field contains the registers of the new thread
+    current_thread: usize,
+    ticks: usize,
+}
+impl State {
+    const DEFAULT: Self = Self {
+        threads: Vec::new(),
+        max_threads: DEFAULT_MAX_THREADS,
+        idle_thread: 0,
+        scheduler: None,
+        wait_queues: BTreeMap::new(),
+        processes: Vec::new(),
+        fpu_owner: None,
+        in_kernel: false,
+        current_thread: 0,
+        ticks: 0,
+    };
+
+    fn thread(&mut self, id: usize) -> &mut Thread {
+        self.get_thread(id).expect("no thread with this id")
+    }
+
+    fn get_thread(&mut self, id: usize) -> Option<&mut Thread> {
+        self.threads.get_mut(id)?.as_deref_mut()
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.thread(self.current_thread)
+    }
+
+    fn scheduler(&mut self) -> &mut dyn Scheduler {
+        self.scheduler
+            .as_deref_mut()
+            .expect("kernel::init was not called")
+    }
+
+    /// marks a thread as ready and hands it to the scheduler
+    fn make_ready(&mut self, id: usize) {
+        let thread = self.thread(id);
+        thread.state = ThreadState::Ready;
+        let priority = thread.priority;
+        if id != self.idle_thread {
+            self.scheduler().enqueue(id, priority);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    /// currently on the cpu
+    Running,
+    /// waiting in the scheduler queues
+    Ready,
+    /// waiting for the given thread to finish
+    Joining(usize),
+    /// waiting for `ticks` to reach the given value
+    Sleeping(usize),
+    /// in the wait queue of the given address
+    Waiting(usize),
+    /// waiting for the given process to end
+    WaitingForProcess(usize),
+    /// finished, its stack is freed by the next `switch_stack_frame`,
+    /// and its id once it has been joined
+    Dead,
+}
+
+struct Thread {
+    state: ThreadState,
+    stack_frame: StackFrame,
+    cpu_regs: CpuRegs,
+    stack_end: usize, // address past the end of the (kernel) stack
+    // None for thread 0, which runs on the boot stack
+    stack: Option<Stack>,
+    // the stack of a ring 3 thread, `stack` is then only used by the interrupt handlers
+    user_stack: Option<Stack>,
+    // Some for the threads of a process, whose user stack is in the process memory
+    process: Option<Pid>,
+    name: String,
+    exit_code: ExitCode,
+    // the exit code has been collected, the id can be reused
+    joined: bool,
+    priority: Priority,
+    // FPU/SSE registers, up to date unless the thread is the fpu owner
+    fpu: Option<FpuState>,
+}
+impl Thread {
+    const DEFAULT: Self = Self {
+        state: ThreadState::Dead,
+        stack_frame: StackFrame::DEFAULT,
+        cpu_regs: CpuRegs::DEFAULT,
+        stack_end: 0,
+        stack: None,
+        user_stack: None,
+        process: None,
+        name: String::new(),
+        exit_code: 0,
+        joined: false,
+        priority: DEFAULT_PRIORITY,
+        fpu: None,
+    };
+
+    fn in_ring_3(&self) -> bool {
+        self.user_stack.is_some() || self.process.is_some()
+    }
+}
+
+struct WaitNode {
+    thread: usize,
+    next: Option<CacheBox<WaitNode>>,
+}
+
+/// the threads waiting on a word, woken up in the order they came
+struct WaitQueue {
+    head: Option<CacheBox<WaitNode>>,
+    // the last node, null when empty
+    tail: *mut WaitNode,
+}
+impl WaitQueue {
+    const fn new() -> Self {
+        Self {
+            head: None,
+            tail: null_mut(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// false if there is no memory left for the node
+    fn push_back(&mut self, thread: usize) -> bool {
+        let mut node = match WAIT_NODES.alloc(WaitNode { thread, next: None }) {
+            Ok(node) => node,
+            Err(_) => return false,
+        };
+        // the node stays in its slot when the box moves
+        let tail: *mut WaitNode = &mut *node;
+        match unsafe { self.tail.as_mut() } {
+            Some(last) => last.next = Some(node),
+            None => self.head = Some(node),
+        }
+        self.tail = tail;
+        true
+    }
+
+    fn pop_front(&mut self) -> Option<usize> {
+        let mut node = self.head.take()?;
+        self.head = node.next.take();
+        if self.head.is_none() {
+            self.tail = null_mut();
+        }
+        Some(node.thread)
+    }
+}
+
+//
+// kernel stuff
+//
+
+/// safety: must be called in a critical section
+///
+/// puts the current thread back in the scheduler queues if it is still running
+pub unsafe fn switch_stack_frame(stack_frame: &mut StackFrame) {
+    reap_dead_threads();
+    process::reap_dead_processes();
+
+    let cur = STATE.current_thread;
+    if STATE.thread(cur).state == ThreadState::Running {
+        // preempted, back in the queues
+        STATE.make_ready(cur);
+    }
+    let next = STATE.scheduler().pick_next().unwrap_or(STATE.idle_thread);
+
+    if cur != next {
+        STATE.thread(cur).stack_frame = *stack_frame;
+
+        let next_thread = STATE.thread(next);
+        *stack_frame = next_thread.stack_frame;
+        set_kernel_stack(next_thread.stack_end);
+        process::activate(next_thread.process);
+    }
+
+    STATE.thread(next).state = ThreadState::Running;
+    STATE.current_thread = next;
+}
+
+// the stacks of `syscall_entry`, which has to switch by itself
+static mut SYSCALL_KERNEL_STACK: usize = 0;
+static mut SYSCALL_USER_STACK: usize = 0;
+
+/// the stack the cpu switches to when the running thread enters the kernel from ring 3
+unsafe fn set_kernel_stack(stack_end: usize) {
+    gdt::set_kernel_stack(VirtAddr::new(stack_end as u64));
+    SYSCALL_KERNEL_STACK = stack_end;
+}
+
+/// frees the stacks of the finished threads, and the ids of the joined ones
+///
+/// the current thread is skipped: while it exits, we are still running on its stack
+unsafe fn reap_dead_threads() {
+    for id in 0..STATE.threads.len() {
+        if id == STATE.current_thread {
+            continue;
+        }
+        let slot = &mut STATE.threads[id];
+        if let Some(thread) = slot {
+            if thread.state == ThreadState::Dead {
+                if thread.joined {
+                    *slot = None;
+                } else {
+                    thread.stack = None;
+                    thread.user_stack = None;
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern "sysv64" fn get_current_regs(dest: *mut CpuRegs) {
+    *dest = STATE.current().cpu_regs;
+
+    // last thing before the `iretq`, the kernel no longer touches the FPU
+    STATE.in_kernel = false;
+    fpu::set_task_switched(STATE.fpu_owner != Some(STATE.current_thread));
+}
+
+pub unsafe fn back_to_thread(stack_frame: *mut StackFrame) -> ! {
+    core::arch::asm!(
+        "mov rsp, {sp}",
+
+        "sub rsp, 15 * 8",
+        "mov rdi, rsp",
+        "call get_current_regs",
+
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        "iretq",
+        sp = in(reg) stack_frame,
+        options(noreturn),
+    );
+}
+
+/// same as `back_to_thread`, for a ring 3 thread that called `syscall`:
+/// its rcx and r11 are lost anyway, `sysretq` takes the return address and the flags there
+unsafe fn sysret_to_thread(stack_frame: *mut StackFrame) -> ! {
+    core::arch::asm!(
+        "mov rsp, {sp}",
+
+        "sub rsp, 15 * 8",
+        "mov rdi, rsp",
+        "call get_current_regs",
+
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        "mov rcx, [rsp]",      // instruction_pointer
+        "mov r11, [rsp + 16]", // cpu_flags
+        "mov rsp, [rsp + 24]", // stack_pointer
+        "sysretq",
+        sp = in(reg) stack_frame,
+        options(noreturn),
+    );
+}
+
+//
+// interrupts
+//
+
+#[no_mangle]
+unsafe extern "sysv64" fn _save_regs_to_current(regs: *const CpuRegs) {
+    STATE.current().cpu_regs = *regs;
+}
+
+/// called first by the timer and syscall handlers, before any code that may use the FPU
+#[no_mangle]
+unsafe extern "sysv64" fn _enter_kernel() {
+    STATE.in_kernel = true;
+    fpu::set_task_switched(true);
+}
+
+macro_rules! save_regs_to_current {
+    () => {
+        core::arch::asm!(
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            "push rbp",
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push rcx",
+            "push rbx",
+            "push rax",
+            "call _enter_kernel",
+            "mov rdi, rsp",
+            "call _save_regs_to_current",
+            "pop rax",
+            "pop rbx",
+            "pop rcx",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop rbp",
+            "pop r8",
+            "pop r9",
+            "pop r10",
+            "pop r11",
+            "pop r12",
+            "pop r13",
+            "pop r14",
+            "pop r15",
+        );
+    };
+}
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let stack_frame_addr = &mut stack_frame as *mut _ as usize;
+    let stack_frame_ptr = stack_frame_addr as *mut StackFrame;
+    unsafe {
+        save_regs_to_current!();
+
+        STATE.ticks += 1;
+        wake_sleeping_threads();
+
+        if preempt_current() {
+            switch_stack_frame(&mut *stack_frame_ptr);
+        }
+
+        interrupts::end_of_interrupt(InterruptIndex::Timer);
+
+        back_to_thread(stack_frame_ptr);
+    }
+}
+
+#[naked]
+pub extern "x86-interrupt" fn system_interrupt_handler(stack_frame: InterruptStackFrame) {
+    unsafe {
+        core::arch::asm!(
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            "push rbp",
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push rcx",
+            "push rbx",
+            "push rax",
+            "call _enter_kernel",
+            "mov rdi, rsp",
+            "call _save_regs_to_current",
+            "lea rdi, [rsp + 15 * 8]", // stack_frame address
+            "call syscall_impl",
+            options(noreturn),
+        );
+    }
+}
+
+/// gives the fpu to the current thread, unless the kernel is the one using it,
+/// returns the area to load its registers from, null for none
+///
+/// the registers of the previous owner are saved already, see
+/// `fpu::device_not_available_handler`
+#[no_mangle]
+unsafe extern "sysv64" fn _switch_fpu() -> *const u8 {
+    STATE.fpu_owner = None;
+    fpu::OWNER_AREA = core::ptr::null_mut();
+    if STATE.in_kernel {
+        return core::ptr::null();
+    }
+    let cur = STATE.current_thread;
+    STATE.fpu_owner = Some(cur);
+    match STATE.get_thread(cur).and_then(|thread| thread.fpu.as_ref()) {
+        Some(state) => {
+            fpu::OWNER_AREA = state.area();
+            state.area()
+        }
+        None => core::ptr::null(),
+    }
+}
+
+/// entry point of the `syscall` instruction, ring 3 only, see `user::init`
+///
+/// builds on the kernel stack the same frame as `int 0x80`,
+/// interrupts stay disabled (through SFMASK) until it is complete
+#[naked]
+pub extern "C" fn syscall_entry() {
+    unsafe {
+        core::arch::asm!(
+            "mov [rip + {user_stack}], rsp",
+            "mov rsp, [rip + {kernel_stack}]",
+            // stack frame, the segments are filled in by `fast_syscall_impl`
+            "push 0",
+            "push qword ptr [rip + {user_stack}]",
+            "push r11", // flags
+            "push 0",
+            "push rcx", // return address
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            "push rbp",
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push rcx",
+            "push rbx",
+            "push rax",
+            "call _enter_kernel",
+            "mov rdi, rsp",
+            "call _save_regs_to_current",
+            "lea rdi, [rsp + 15 * 8]", // stack_frame address
+            "call fast_syscall_impl",
+            user_stack = sym SYSCALL_USER_STACK,
+            kernel_stack = sym SYSCALL_KERNEL_STACK,
+            options(noreturn),
+        );
+    }
+}
+
+fn idle() {
+    crate::hlt_loop();
+}
+
+/// called by the page fault handler first, returns whether the fault at `addr` was
+/// resolved and the access can be retried, see `process::handle_page_fault`
+pub unsafe fn resolve_page_fault(addr: VirtAddr, write: bool, present: bool) -> bool {
+    process::handle_page_fault(addr, write, present)
+}
+
+/// whether an exception handler may end the current thread rather than panic:
+/// it is not the boot thread nor the idle one, and the kernel is not working for it
+pub unsafe fn can_kill_current_thread() -> bool {
+    let cur = STATE.current_thread;
+    !STATE.in_kernel && cur != 0 && cur != STATE.idle_thread
+}
+
+/// called by the page fault handler, on its own stack, with the faulting address
+///
+/// if it is in the guard page of a thread stack, reports the overflow,
+/// kills the thread and does not return: the next thread runs instead
+pub unsafe fn check_stack_overflow(addr: VirtAddr, stack_frame: *mut StackFrame) {
+    let owner = STATE.threads.iter().position(|slot| match slot {
+        Some(thread) => [&thread.stack, &thread.user_stack]
+            .iter()
+            .any(|stack| matches!(stack, Some(stack) if stack.guard_contains(addr))),
+        None => false,
+    });
+    let id = match owner {
+        Some(id) => id,
+        None => return,
+    };
+    crate::kprintln!("stack overflow in thread {}", id);
+    if id != STATE.current_thread {
+        panic!(
+            "thread {} hit the stack guard of thread {}",
+            STATE.current_thread, id
+        );
+    }
+
+    kill_current_thread(STACK_OVERFLOW_EXIT_CODE, stack_frame);
+}
+
+/// called by the exception handlers, ends the current thread with `code`
+/// and runs the next one instead
+///
+/// the thread of a process takes down the whole process
+pub unsafe fn kill_current_thread(code: ExitCode, stack_frame: *mut StackFrame) -> ! {
+    let cur = STATE.current_thread;
+    exit_thread(process::main_thread_of(cur).unwrap_or(cur), code);
+    switch_stack_frame(&mut *stack_frame);
+    back_to_thread(stack_frame);
+}
+
+unsafe fn wake_sleeping_threads() {
+    let now = STATE.ticks;
+    for id in 0..STATE.threads.len() {
+        match STATE.get_thread(id) {
+            Some(thread) if matches!(thread.state, ThreadState::Sleeping(until) if until <= now) => {
+                STATE.make_ready(id)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// asks the scheduler whether the current thread is done with the cpu for now
+unsafe fn preempt_current() -> bool {
+    let cur = STATE.current_thread;
+    if cur == STATE.idle_thread {
+        return true;
+    }
+    let priority = STATE.thread(cur).priority;
+    STATE.scheduler().tick(cur, priority)
+}
+
+#[no_mangle]
+unsafe extern "sysv64" fn _thread_start(main: *mut ThreadMain) -> ! {
+    let main = Box::from_raw(main);
+    main();
+    exit(0);
+}
+
+#[no_mangle]
+unsafe extern "sysv64" fn syscall_impl(stack_frame: *mut StackFrame) -> ! {
+    handle_syscall(stack_frame);
+    back_to_thread(stack_frame);
+}
+
+/// `syscall_impl` for the threads entering through `syscall_entry`
+#[no_mangle]
+unsafe extern "sysv64" fn fast_syscall_impl(stack_frame: *mut StackFrame) -> ! {
+    let caller = STATE.current_thread;
+    if !STATE.thread(caller).in_ring_3() {
+        // `syscall_entry` just overwrote the top of its stack
+        panic!("thread {} used the syscall instruction in ring 0", caller);
+    }
+    let selectors = gdt::selectors();
+    (*stack_frame).code_segment = selectors.user_code.0 as u64;
+    (*stack_frame).stack_segment = selectors.user_data.0 as u64;
+
+    handle_syscall(stack_frame);
+    if STATE.current_thread == caller {
+        sysret_to_thread(stack_frame);
+    }
+    back_to_thread(stack_frame);
+}
+
+/// the registers of the calling thread are already saved, see `syscall::dispatch`
+unsafe fn handle_syscall(stack_frame: *mut StackFrame) {
+    let caller = STATE.current_thread;
+    syscall::dispatch(caller, &*stack_frame);
+
+    if STATE.thread(caller).state != ThreadState::Running {
+        switch_stack_frame(&mut *stack_frame);
+    }
+}
+
+unsafe fn exit_thread(id: usize, code: ExitCode) {
+    let thread = STATE.thread(id);
+    thread.state = ThreadState::Dead;
+    thread.exit_code = code;
+    crate::sprintln!(
+        "thread {} {:?} finished with code {}",
+        id,
+        thread.name,
+        code
+    );
+
+    STATE.scheduler().forget(id);
+    if STATE.fpu_owner == Some(id) {
+        STATE.fpu_owner = None;
+        fpu::OWNER_AREA = core::ptr::null_mut();
+    }
+
+    let mut joined = false;
+    for joiner_id in 0..STATE.threads.len() {
+        match STATE.get_thread(joiner_id) {
+            Some(joiner) if joiner.state == ThreadState::Joining(id) => {
+                joiner.cpu_regs.rax = code as u64;
+                STATE.make_ready(joiner_id);
+                joined = true;
+            }
+            _ => {}
+        }
+    }
+    // the exit code of a process thread is collected by `wait_process`
+    STATE.thread(id).joined = joined || STATE.thread(id).process.is_some();
+
+    process::thread_exited(id, code);
+}
+
+/// returns 1 if the caller went to sleep, 0 if the word had already changed
+///
+/// fails with `OutOfMemory` without room for its wait node
+unsafe fn wait_on_word(caller: usize, addr: usize, expected: u32) -> SyscallResult {
+    // interrupts are disabled: nobody can change the word between this check and the sleep
+    let word = &*(addr as *const AtomicU32);
+    if word.load(Ordering::SeqCst) != expected {
+        return Ok(0);
+    }
+    let queue = STATE.wait_queues.entry(addr).or_insert_with(WaitQueue::new);
+    if !queue.push_back(caller) {
+        if queue.is_empty() {
+            STATE.wait_queues.remove(&addr);
+        }
+        return Err(Errno::OutOfMemory);
+    }
+    STATE.thread(caller).state = ThreadState::Waiting(addr);
+    Ok(1)
+}
+
+/// returns the number of threads woken up
+unsafe fn wake_word(addr: usize, count: usize) -> SyscallResult {
+    let mut woken = 0;
+    while woken < count {
+        let id = match STATE
+            .wait_queues
+            .get_mut(&addr)
+            .and_then(|queue| queue.pop_front())
+        {
+            Some(id) => id,
+            None => break,
+        };
+        STATE.make_ready(id);
+        woken += 1;
+    }
+    if matches!(STATE.wait_queues.get(&addr), Some(queue) if queue.is_empty()) {
+        STATE.wait_queues.remove(&addr);
+    }
+    Ok(woken)
+}
+
+unsafe fn set_thread_priority(id: usize, priority: Priority) -> SyscallResult {
+    let priority = priority.min(MAX_PRIORITY);
+    let state = match STATE.get_thread(id) {
+        Some(thread) if thread.state != ThreadState::Dead => {
+            thread.priority = priority;
+            thread.state
+        }
+        _ => return Err(Errno::NoSuchThread),
+    };
+    if state == ThreadState::Ready && id != STATE.idle_thread {
+        STATE.scheduler().dequeue(id);
+        STATE.scheduler().enqueue(id, priority);
+    }
+    Ok(0)
+}
+
+/// returns the exit code of `target`, `WouldBlock` while it is running
+///
+/// with `block`, the caller waits for it instead, and gets its exit code from `exit_thread`
+unsafe fn join_thread(caller: usize, target: usize, block: bool) -> SyscallResult {
+    match STATE.get_thread(target) {
+        _ if target == caller => Err(Errno::NoSuchThread),
+        None => Err(Errno::NoSuchThread),
+        Some(thread) if thread.joined => Err(Errno::NoSuchThread),
+        Some(thread) if thread.state == ThreadState::Dead => {
+            thread.joined = true;
+            Ok(thread.exit_code)
+        }
+        Some(_) => {
+            if block {
+                STATE.thread(caller).state = ThreadState::Joining(target);
+            }
+            Err(Errno::WouldBlock)
+        }
+    }
+}
+
+/// returns the id of the new thread
+unsafe fn launch_thread(spec: &LaunchSpec, stack_frame: &StackFrame) -> SyscallResult {
+    check_thread_limit()?;
+
+    let entry = match spec.user_entry {
+        Some(entry) => ThreadEntry::User(entry),
+        None => ThreadEntry::Kernel(spec.main),
+    };
+    let cpu_flags = new_thread_flags(stack_frame.cpu_flags);
+    let mut child = match new_thread(entry, cpu_flags, spec.stack_size) {
+        Some(child) => child,
+        None => {
+            crate::sprintln!("launch: no room left for a {} bytes stack", spec.stack_size);
+            return Err(Errno::OutOfMemory);
+        }
+    };
+    child.priority = spec.priority.min(MAX_PRIORITY);
+    let name = core::slice::from_raw_parts(spec.name_ptr, spec.name_len);
+    child.name = String::from(core::str::from_utf8(name).unwrap_or("?"));
+
+    Ok(add_thread(child))
+}
+
+/// fails past `set_max_threads`
+unsafe fn check_thread_limit() -> Result<(), Errno> {
+    let used_ids = STATE.threads.iter().filter(|slot| slot.is_some()).count();
+    if used_ids >= STATE.max_threads {
+        crate::sprintln!("launch: too many threads ({})", used_ids);
+        return Err(Errno::TooManyThreads);
+    }
+    Ok(())
+}
+
+/// the flags a new thread starts with, from those of the thread launching it
+fn new_thread_flags(cpu_flags: u64) -> u64 {
+    // clear: CF, PF, AF, ZF, SF, TF, DF, OF,
+    cpu_flags
+        & !(
+            // CF
+            0x0001
+            // PF
+            | 0x0004
+            // AF
+            | 0x0010
+            // ZF
+            | 0x0040
+            // SF
+            | 0x0080
+            // DF
+            | 0x0400
+            // OF
+            | 0x0800
+        )
+}
+
+/// gives the ready thread the lowest free id, and returns it
+unsafe fn add_thread(thread: CacheBox<Thread>) -> usize {
+    let id = match STATE.threads.iter().position(|slot| slot.is_none()) {
+        Some(id) => id,
+        None => {
+            STATE.threads.push(None);
+            STATE.threads.len() - 1
+        }
+    };
+    STATE.threads[id] = Some(thread);
+    STATE.make_ready(id);
+    crate::sprintln!(
+        "new thread: {} {:?} ({} used ids)",
+        id,
+        STATE.thread(id).name,
+        STATE.threads.iter().filter(|slot| slot.is_some()).count()
+    );
+    id
+}
+
+/// what a new thread runs
+enum ThreadEntry {
+    /// the closure, through `_thread_start` in ring 0
+    Kernel(*mut ThreadMain),
+    /// the function in ring 3, on a stack of its own
+    User(UserEntry),
+    /// the function in ring 3, on the given stack pointer in the memory of its process
+    Process(usize, usize),
+}
+
+/// allocates a ready thread, None if there is no room left for its stacks
+unsafe fn new_thread(
+    entry: ThreadEntry,
+    cpu_flags: u64,
+    stack_size: usize,
+) -> Option<CacheBox<Thread>> {
+    let selectors = gdt::selectors();
+    let new_stack = Stack::new(match entry {
+        ThreadEntry::Kernel(_) => stack_size.max(MIN_STACK_SIZE),
+        ThreadEntry::User(_) | ThreadEntry::Process(..) => DEFAULT_STACK_SIZE,
+    })?;
+
+    let mut thread = THREADS.alloc(Thread::DEFAULT).ok()?;
+    thread.stack_end = new_stack.end();
+    thread.stack = Some(new_stack);
+    thread.stack_frame.cpu_flags = cpu_flags;
+
+    match entry {
+        ThreadEntry::Kernel(main) => {
+            // aligned on 16 bytes, minus the return address a `call` would have pushed
+            let new_stack_addr = thread.stack_end - size_of::<usize>();
+            crate::sprintln!("new stack pointer: {:x}", new_stack_addr);
+
+            thread.stack_frame.instruction_pointer = _thread_start as u64;
+            thread.stack_frame.code_segment = selectors.kernel_code.0 as u64;
+            thread.stack_frame.stack_pointer = new_stack_addr as u64;
+            thread.stack_frame.stack_segment = selectors.kernel_data.0 as u64;
+
+            // closure to be executed by _thread_start
+            thread.cpu_regs.rdi = main as u64;
+        }
+        ThreadEntry::User(entry) => {
+            let user_stack = Stack::new_user(stack_size.max(MIN_STACK_SIZE))?;
+            // `entry` returns to `user_return`
+            let new_stack_addr = user_stack.end() - size_of::<usize>();
+            *(new_stack_addr as *mut usize) = user::user_return as usize;
+            crate::sprintln!("new user stack pointer: {:x}", new_stack_addr);
+
+            thread.stack_frame.instruction_pointer = entry as usize as u64;
+            thread.stack_frame.code_segment = selectors.user_code.0 as u64;
+            thread.stack_frame.stack_pointer = new_stack_addr as u64;
+            thread.stack_frame.stack_segment = selectors.user_data.0 as u64;
+            thread.user_stack = Some(user_stack);
+        }
+        ThreadEntry::Process(entry, stack_pointer) => {
+            thread.stack_frame.instruction_pointer = entry as u64;
+            thread.stack_frame.code_segment = selectors.user_code.0 as u64;
+            thread.stack_frame.stack_pointer = stack_pointer as u64;
+            thread.stack_frame.stack_segment = selectors.user_data.0 as u64;
+        }
+    }
+
+    thread.fpu = Some(FpuState::new());
+
+    thread.state = ThreadState::Ready;
+    Some(thread)
+}
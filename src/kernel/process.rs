@@ -0,0 +1,487 @@
+//! Processes: ring 3 threads with an address space of their own.
+//!
+//! A process sees the kernel mappings like any thread, plus its private region,
+//! `paging::process_region`: its heap at the start, then the stacks of its threads,
+//! with unmapped pages between them as guards, then its program if it was loaded
+//! from an ELF file, see `elf`. The other processes don't see this region, so a
+//! process that crashes only takes down its own threads.
+//!
+//! The heap and the stacks are only reserved: their pages are mapped when first
+//! touched, see `handle_page_fault`. A page read before it is written is the shared
+//! frame of zeros, copied on the first write.
+//!
+//! A process ends with its main thread, with its exit code, or when one of its
+//! threads faults, with `FAULT_EXIT_CODE`. Its other threads are killed then.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::syscall::{self, SyscallResult};
+use super::{user, Errno, ExitCode, LaunchError, StackFrame, ThreadEntry, ThreadState, UserEntry};
+use super::{DEFAULT_STACK_SIZE, STATE};
+use crate::system::paging::{self, AddressSpace};
+
+pub type Pid = usize;
+
+const PAGE_SIZE: usize = 4096;
+/// the heap starts at the beginning of the region
+const MAX_HEAP_SIZE: usize = 256 * 1024 * 1024;
+const STACKS_OFFSET: u64 = 1 << 30;
+/// where the ELF programs are loaded, up to the end of the region
+pub(super) const PROGRAMS_OFFSET: u64 = 4 << 30;
+pub(super) const REGION_SIZE: u64 = 512 << 30;
+/// a stack, up to the guard page at the bottom
+const STACK_SLOT_SIZE: u64 = 64 * 1024;
+/// the stacks of the threads that exited are only freed with the process
+const MAX_THREADS_PER_PROCESS: usize = 16;
+
+pub struct Process {
+    name: String,
+    // None once the process has ended and its memory has been freed
+    address_space: Option<AddressSpace>,
+    // ids of the threads alive
+    threads: Vec<usize>,
+    main_thread: usize,
+    // stack slots used so far
+    stacks: usize,
+    heap_size: usize,
+    exit_code: Option<ExitCode>,
+    // the exit code has been collected, the pid can be reused
+    waited: bool,
+}
+
+/// starts a process whose main thread runs `entry`, see `user`
+pub fn spawn_process(name: &str, entry: UserEntry) -> Result<Pid, LaunchError> {
+    syscall::int::spawn_process(entry as usize, name.as_ptr(), name.len()).map_err(|errno| {
+        match errno {
+            Errno::TooManyThreads => LaunchError::TooManyThreads,
+            Errno::OutOfMemory => LaunchError::OutOfStackSpace,
+            errno => unreachable!("spawn_process: {:?}", errno),
+        }
+    })
+}
+
+/// waits for the process `pid` to end and returns its exit code
+pub fn wait_process(pid: Pid) -> ExitCode {
+    match syscall::int::wait_process(pid) {
+        Ok(code) => code,
+        Err(errno) => panic!("wait_process: process {}: {:?}", pid, errno),
+    }
+}
+
+/// the SpawnProcess syscall
+pub(super) unsafe fn spawn(entry: usize, name: &str, stack_frame: &StackFrame) -> SyscallResult {
+    if !user::in_user_text(entry) {
+        return Err(Errno::InvalidArgument);
+    }
+    super::check_thread_limit()?;
+    let address_space = AddressSpace::new().ok_or(Errno::OutOfMemory)?;
+    let pid = new_process(name, address_space);
+    let main_thread = map_stack(pid).and_then(|(stack_end, top_page)| {
+        let stack_pointer = push_return_address(stack_end, top_page);
+        start_thread(pid, entry, stack_pointer, stack_frame)
+    });
+    started(pid, main_thread)
+}
+
+/// the SpawnThread syscall, the new thread runs `entry` in the caller's process
+pub(super) unsafe fn spawn_thread(
+    caller: usize,
+    entry: usize,
+    stack_frame: &StackFrame,
+) -> SyscallResult {
+    let pid = STATE.thread(caller).process.ok_or(Errno::NoSuchProcess)?;
+    if !user::in_user_text(entry) {
+        return Err(Errno::InvalidArgument);
+    }
+    super::check_thread_limit()?;
+    let (stack_end, top_page) = map_stack(pid)?;
+    let stack_pointer = push_return_address(stack_end, top_page);
+    start_thread(pid, entry, stack_pointer, stack_frame)
+}
+
+/// registers a process without any thread yet
+pub(super) unsafe fn new_process(name: &str, address_space: AddressSpace) -> Pid {
+    let pid = match STATE.processes.iter().position(|slot| slot.is_none()) {
+        Some(pid) => pid,
+        None => {
+            STATE.processes.push(None);
+            STATE.processes.len() - 1
+        }
+    };
+    STATE.processes[pid] = Some(Box::new(Process {
+        name: String::from(name),
+        address_space: Some(address_space),
+        threads: Vec::new(),
+        main_thread: 0,
+        stacks: 0,
+        heap_size: 0,
+        exit_code: None,
+        waited: false,
+    }));
+    pid
+}
+
+/// makes `main_thread` the main thread of `pid`, or drops the process if it failed to start
+pub(super) unsafe fn started(pid: Pid, main_thread: SyscallResult) -> SyscallResult {
+    match main_thread {
+        Ok(main_thread) => {
+            let process = process(pid);
+            process.main_thread = main_thread;
+            crate::sprintln!(
+                "new process: {} {:?}, main thread {}",
+                pid,
+                process.name,
+                main_thread
+            );
+            Ok(pid)
+        }
+        Err(errno) => {
+            STATE.processes[pid] = None;
+            Err(errno)
+        }
+    }
+}
+
+/// maps the top page of a stack for a new thread of `pid`, returns its end and the address
+/// the kernel sees the page at
+///
+/// the rest of the stack slot is mapped on demand, but for its guard page
+pub(super) unsafe fn map_stack(pid: Pid) -> Result<(usize, *mut u8), Errno> {
+    let process = process(pid);
+    if process.stacks == MAX_THREADS_PER_PROCESS {
+        return Err(Errno::TooManyThreads);
+    }
+    process.stacks += 1;
+    let slot_end =
+        paging::process_region() + STACKS_OFFSET + process.stacks as u64 * STACK_SLOT_SIZE;
+
+    let space = process.address_space.as_mut().unwrap();
+    let top_page = Page::containing_address(slot_end - PAGE_SIZE as u64);
+    let top_page = space
+        .map_zeroed(top_page, user_data_flags())
+        .ok_or(Errno::OutOfMemory)?;
+    Ok((slot_end.as_u64() as usize, top_page))
+}
+
+/// `entry` returns to `user_return`, returns the stack pointer below its address
+unsafe fn push_return_address(stack_end: usize, top_page: *mut u8) -> usize {
+    let return_slot = top_page.add(PAGE_SIZE - size_of::<usize>()) as *mut usize;
+    *return_slot = user::user_return as usize;
+    stack_end - size_of::<usize>()
+}
+
+/// starts a thread of `pid` running `entry` on `stack_pointer`, returns its id
+pub(super) unsafe fn start_thread(
+    pid: Pid,
+    entry: usize,
+    stack_pointer: usize,
+    stack_frame: &StackFrame,
+) -> SyscallResult {
+    let cpu_flags = super::new_thread_flags(stack_frame.cpu_flags);
+    let mut thread = super::new_thread(
+        ThreadEntry::Process(entry, stack_pointer),
+        cpu_flags,
+        DEFAULT_STACK_SIZE,
+    )
+    .ok_or(Errno::OutOfMemory)?;
+    let process = process(pid);
+    thread.process = Some(pid);
+    thread.name = process.name.clone();
+    let id = super::add_thread(thread);
+    process.threads.push(id);
+    Ok(id)
+}
+
+/// the GrowHeap syscall, adds `bytes` (rounded up to pages) at the end of the heap
+/// of the caller's process, returns the address of the new memory
+///
+/// the pages are mapped when first touched
+pub(super) unsafe fn grow_heap(caller: usize, bytes: usize) -> SyscallResult {
+    let pid = STATE.thread(caller).process.ok_or(Errno::NoSuchProcess)?;
+    let process = process(pid);
+    let start = paging::process_region() + process.heap_size as u64;
+    if bytes > MAX_HEAP_SIZE - process.heap_size {
+        return Err(Errno::OutOfMemory);
+    }
+    process.heap_size += bytes.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    Ok(start.as_u64() as usize)
+}
+
+/// called by the page fault handler for a fault at `addr` in the current process,
+/// returns whether the access can be retried
+///
+/// maps the untouched pages of the heap and the stacks, and copies the copy-on-write
+/// pages on a write
+pub(super) unsafe fn handle_page_fault(addr: VirtAddr, write: bool, present: bool) -> bool {
+    let region = paging::process_region();
+    if addr < region || addr - region >= REGION_SIZE {
+        return false;
+    }
+    let process = match STATE
+        .get_thread(STATE.current_thread)
+        .and_then(|thread| thread.process)
+    {
+        Some(pid) => process(pid),
+        None => return false,
+    };
+    let offset = addr - region;
+    let lazy = in_lazy_range(process, offset);
+    let space = match process.address_space.as_mut() {
+        Some(space) => space,
+        None => return false,
+    };
+    let page = Page::containing_address(addr);
+    match (present, write) {
+        (true, true) => space.copy_on_write(page),
+        (true, false) => false,
+        (false, true) if lazy => space.map_zeroed(page, user_data_flags()).is_some(),
+        (false, false) if lazy => space.map_zero_page(page, user_data_flags()).is_some(),
+        (false, _) => false,
+    }
+}
+
+/// whether the page at `offset` in the region is in the heap, or in a stack above its guard page
+fn in_lazy_range(process: &Process, offset: u64) -> bool {
+    if offset < process.heap_size as u64 {
+        return true;
+    }
+    let stacks_end = STACKS_OFFSET + process.stacks as u64 * STACK_SLOT_SIZE;
+    (STACKS_OFFSET..stacks_end).contains(&offset)
+        && (offset - STACKS_OFFSET) % STACK_SLOT_SIZE >= PAGE_SIZE as u64
+}
+
+/// the WaitProcess syscall, returns the exit code of `pid` if it has ended,
+/// otherwise the caller waits for it and gets it from `thread_exited`
+pub(super) unsafe fn wait(caller: usize, pid: Pid) -> SyscallResult {
+    match STATE.processes.get_mut(pid) {
+        Some(Some(process)) if !process.waited => match process.exit_code {
+            Some(code) => {
+                process.waited = true;
+                Ok(code)
+            }
+            None => {
+                STATE.thread(caller).state = ThreadState::WaitingForProcess(pid);
+                Err(Errno::WouldBlock)
+            }
+        },
+        _ => Err(Errno::NoSuchProcess),
+    }
+}
+
+/// the thread to end to take down the whole process of `id`, if it is in one
+pub(super) unsafe fn main_thread_of(id: usize) -> Option<usize> {
+    let pid = STATE.get_thread(id)?.process?;
+    Some(process(pid).main_thread)
+}
+
+/// called by `exit_thread`, ends the process with its main thread
+pub(super) unsafe fn thread_exited(id: usize, code: ExitCode) {
+    let pid = match STATE.thread(id).process {
+        Some(pid) => pid,
+        None => return,
+    };
+    let process = process(pid);
+    process.threads.retain(|&thread| thread != id);
+    if id != process.main_thread {
+        return;
+    }
+
+    for thread in core::mem::take(&mut process.threads) {
+        super::exit_thread(thread, code);
+    }
+    let process = self::process(pid);
+    process.exit_code = Some(code);
+    crate::sprintln!(
+        "process {} {:?} ended with code {}",
+        pid,
+        process.name,
+        code
+    );
+
+    for waiter in 0..STATE.threads.len() {
+        match STATE.get_thread(waiter) {
+            Some(thread) if thread.state == ThreadState::WaitingForProcess(pid) => {
+                thread.cpu_regs.rax = code as u64;
+                STATE.make_ready(waiter);
+                self::process(pid).waited = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// frees the memory of the processes that ended, and the pids of the waited ones
+///
+/// the process of the current thread is skipped: its tables are still active
+pub(super) unsafe fn reap_dead_processes() {
+    let current = STATE.current().process;
+    for pid in 0..STATE.processes.len() {
+        let slot = &mut STATE.processes[pid];
+        if let Some(process) = slot {
+            if process.exit_code.is_some() && current != Some(pid) {
+                process.address_space = None;
+                if process.waited {
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
+
+/// switches to the address space of `pid`, or to the kernel's
+///
+/// safety: must be called in a critical section
+pub(super) unsafe fn activate(pid: Option<Pid>) {
+    let space = pid.and_then(|pid| process(pid).address_space.as_ref());
+    paging::activate(space);
+}
+
+unsafe fn process(pid: Pid) -> &'static mut Process {
+    STATE
+        .processes
+        .get_mut(pid)
+        .and_then(|slot| slot.as_deref_mut())
+        .expect("no process with this id")
+}
+
+fn user_data_flags() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{join, launch, user, FAULT_EXIT_CODE};
+    use crate::system::regions::{self, Region};
+
+    #[link_section = "user_text"]
+    extern "C" fn sum_to_ten() {
+        let mut sum: usize = 0;
+        let mut i: usize = 1;
+        while i <= 10 {
+            sum = sum.wrapping_add(i);
+            i = i.wrapping_add(1);
+        }
+        user::exit(sum);
+    }
+
+    #[test_case]
+    fn test_process_exits_with_main_thread_code() {
+        let pid = spawn_process("sum", sum_to_ten).unwrap();
+        assert_eq!(wait_process(pid), 55);
+    }
+
+    /// writes the time at the start of its heap, and exits with whether it still
+    /// finds it there after the other processes had a chance to do the same
+    #[link_section = "user_text"]
+    extern "C" fn write_heap() {
+        let heap = match user::grow_heap(1) {
+            Ok(heap) => heap as *mut u64,
+            Err(_) => user::exit(0),
+        };
+        let low: u32;
+        let high: u32;
+        unsafe { core::arch::asm!("rdtsc", out("eax") low, out("edx") high) };
+        let time = ((high as u64) << 32) | low as u64;
+        unsafe { heap.write_volatile(time) };
+        user::sleep(5);
+        user::exit((unsafe { heap.read_volatile() } == time) as usize);
+    }
+
+    #[test_case]
+    fn test_heaps_are_private() {
+        let pids = [0; 3].map(|_| spawn_process("heap", write_heap).unwrap());
+        assert_eq!(pids.map(wait_process), [1; 3]);
+    }
+
+    /// sleeps until its thread crashes
+    #[link_section = "user_text"]
+    extern "C" fn crash_in_thread() {
+        if let Err(errno) = user::spawn_thread(scribble) {
+            user::exit(errno as usize);
+        }
+        loop {
+            user::sleep(1);
+        }
+    }
+
+    #[link_section = "user_text"]
+    extern "C" fn scribble() {
+        let vga_text_buffer = 0xb8000 as *mut u8;
+        unsafe { *vga_text_buffer = b'!' };
+    }
+
+    #[test_case]
+    fn test_crash_is_contained() {
+        let crashing = spawn_process("crash", crash_in_thread).unwrap();
+        let other = spawn_process("sum", sum_to_ten).unwrap();
+        assert_eq!(wait_process(crashing), FAULT_EXIT_CODE);
+        assert_eq!(wait_process(other), 55);
+    }
+
+    /// reserves 64 MiB of heap, which reads as zeros then keeps what is written,
+    /// exits with the number of pages that did
+    #[link_section = "user_text"]
+    extern "C" fn touch_large_heap() {
+        const SIZE: usize = 64 * 1024 * 1024;
+        const STEP: usize = 1024 * 1024;
+        let heap = match user::grow_heap(SIZE) {
+            Ok(heap) => heap,
+            Err(_) => user::exit(0),
+        };
+        let mut good: usize = 0;
+        let mut offset: usize = 0;
+        while offset < SIZE {
+            let word = unsafe { heap.add(offset) } as *mut usize;
+            if unsafe { word.read_volatile() } == 0 {
+                unsafe { word.write_volatile(offset) };
+                if unsafe { word.read_volatile() } == offset {
+                    good = good.wrapping_add(1);
+                }
+            }
+            offset = offset.wrapping_add(STEP);
+        }
+        user::exit(good);
+    }
+
+    #[test_case]
+    fn test_heap_is_mapped_on_demand() {
+        let pid = spawn_process("large heap", touch_large_heap).unwrap();
+        assert_eq!(wait_process(pid), 64);
+    }
+
+    /// writes 32 KiB below its stack pointer, well past the page mapped at first
+    #[link_section = "user_text"]
+    extern "C" fn probe_stack() {
+        let value: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov qword ptr [rsp - 32768], 42",
+                "mov {}, [rsp - 32768]",
+                out(reg) value,
+            )
+        };
+        user::exit(value);
+    }
+
+    #[test_case]
+    fn test_stack_grows_on_demand() {
+        let pid = spawn_process("stack", probe_stack).unwrap();
+        assert_eq!(wait_process(pid), 42);
+    }
+
+    #[test_case]
+    fn test_invalid_access_kills_kernel_thread() {
+        fn write_unmapped() {
+            let page = regions::allocate(Region::Kernel, 1).unwrap();
+            unsafe { page.start_address().as_mut_ptr::<u8>().write_volatile(1) };
+        }
+        let id = launch(write_unmapped).unwrap();
+        assert_eq!(join(id), FAULT_EXIT_CODE);
+    }
+}
@@ -0,0 +1,274 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// higher runs first, from 0 to `MAX_PRIORITY`
+pub type Priority = u8;
+
+pub const MAX_PRIORITY: Priority = 7;
+pub const DEFAULT_PRIORITY: Priority = 3;
+/// for the threads the user waits on, e.g. the shell, above the compute threads
+pub const INTERACTIVE_PRIORITY: Priority = 5;
+
+/// A scheduling policy.
+///
+/// The kernel keeps track of the thread states, the policy only sees the ready threads:
+/// a thread is given to `enqueue` when it becomes ready and leaves through `pick_next`
+/// (or `dequeue`). The running thread and the idle thread are never queued.
+pub trait Scheduler {
+    fn name(&self) -> &'static str;
+
+    /// `id` is ready to run
+    fn enqueue(&mut self, id: usize, priority: Priority);
+
+    /// removes a ready thread, e.g. before requeuing it with another priority
+    fn dequeue(&mut self, id: usize);
+
+    /// takes the next thread to run out of the queues, None to run the idle thread
+    fn pick_next(&mut self) -> Option<usize>;
+
+    /// called at every timer tick while `current` runs,
+    /// returns whether it has to give the cpu back
+    fn tick(&mut self, current: usize, priority: Priority) -> bool;
+
+    /// `id` finished, its id may be reused by a new thread
+    fn forget(&mut self, _id: usize) {}
+}
+
+fn remove_from(queue: &mut VecDeque<usize>, id: usize) {
+    queue.retain(|&queued| queued != id);
+}
+
+/// Every thread runs one tick in turn, priorities are ignored.
+#[derive(Default)]
+pub struct RoundRobin {
+    queue: VecDeque<usize>,
+}
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+    fn enqueue(&mut self, id: usize, _: Priority) {
+        self.queue.push_back(id);
+    }
+    fn dequeue(&mut self, id: usize) {
+        remove_from(&mut self.queue, id);
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queue.pop_front()
+    }
+    fn tick(&mut self, _: usize, _: Priority) -> bool {
+        !self.queue.is_empty()
+    }
+}
+
+/// The highest priority ready thread always runs,
+/// threads of the same priority share the cpu in round robin.
+/// Low priority threads starve as long as a higher priority one is runnable.
+#[derive(Default)]
+pub struct FixedPriority {
+    // indexed by priority
+    queues: [VecDeque<usize>; MAX_PRIORITY as usize + 1],
+}
+impl FixedPriority {
+    pub const fn new() -> Self {
+        const EMPTY: VecDeque<usize> = VecDeque::new();
+        Self {
+            queues: [EMPTY; MAX_PRIORITY as usize + 1],
+        }
+    }
+}
+impl Scheduler for FixedPriority {
+    fn name(&self) -> &'static str {
+        "fixed-priority"
+    }
+    fn enqueue(&mut self, id: usize, priority: Priority) {
+        self.queues[priority.min(MAX_PRIORITY) as usize].push_back(id);
+    }
+    fn dequeue(&mut self, id: usize) {
+        for queue in self.queues.iter_mut() {
+            remove_from(queue, id);
+        }
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        self.queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+    }
+    fn tick(&mut self, _: usize, priority: Priority) -> bool {
+        // preempted by the same priority too, to round robin between equals
+        self.queues[priority.min(MAX_PRIORITY) as usize..]
+            .iter()
+            .any(|queue| !queue.is_empty())
+    }
+}
+
+const MLFQ_LEVELS: usize = 3;
+/// ticks a thread may run before being demoted, indexed by level
+const MLFQ_QUANTUM: [usize; MLFQ_LEVELS] = [1, 2, 4];
+/// every thread goes back to the top level this often (in ticks), so nobody starves
+const MLFQ_BOOST_PERIOD: usize = 100;
+
+/// Multi-level feedback queue.
+///
+/// Threads start at the top level (0) and go down one level each time they use
+/// their whole quantum, so interactive threads that block early stay on top.
+/// Priorities are ignored.
+#[derive(Default)]
+pub struct Mlfq {
+    queues: [VecDeque<usize>; MLFQ_LEVELS],
+    // indexed by thread id
+    levels: Vec<usize>,
+    // ticks used by the running thread in its current quantum
+    used: usize,
+    ticks_since_boost: usize,
+}
+impl Mlfq {
+    pub const fn new() -> Self {
+        const EMPTY: VecDeque<usize> = VecDeque::new();
+        Self {
+            queues: [EMPTY; MLFQ_LEVELS],
+            levels: Vec::new(),
+            used: 0,
+            ticks_since_boost: 0,
+        }
+    }
+
+    fn level(&self, id: usize) -> usize {
+        self.levels.get(id).copied().unwrap_or(0)
+    }
+
+    fn set_level(&mut self, id: usize, level: usize) {
+        if id >= self.levels.len() {
+            self.levels.resize(id + 1, 0);
+        }
+        self.levels[id] = level;
+    }
+
+    fn boost(&mut self) {
+        self.levels.fill(0);
+        for level in 1..MLFQ_LEVELS {
+            while let Some(id) = self.queues[level].pop_front() {
+                self.queues[0].push_back(id);
+            }
+        }
+    }
+}
+impl Scheduler for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+    fn enqueue(&mut self, id: usize, _: Priority) {
+        let level = self.level(id);
+        self.queues[level].push_back(id);
+    }
+    fn dequeue(&mut self, id: usize) {
+        for queue in self.queues.iter_mut() {
+            remove_from(queue, id);
+        }
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        self.used = 0;
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+    fn tick(&mut self, current: usize, _: Priority) -> bool {
+        self.ticks_since_boost += 1;
+        if self.ticks_since_boost >= MLFQ_BOOST_PERIOD {
+            self.ticks_since_boost = 0;
+            self.boost();
+        }
+
+        let level = self.level(current);
+        self.used += 1;
+        if self.used >= MLFQ_QUANTUM[level] {
+            self.set_level(current, (level + 1).min(MLFQ_LEVELS - 1));
+            self.used = 0;
+            return self.queues.iter().any(|queue| !queue.is_empty());
+        }
+        // a thread woke up on a higher level
+        self.queues[..level].iter().any(|queue| !queue.is_empty())
+    }
+    fn forget(&mut self, id: usize) {
+        self.set_level(id, 0);
+    }
+}
+
+#[test_case]
+fn test_round_robin_order() {
+    let mut scheduler = RoundRobin::new();
+    scheduler.enqueue(1, DEFAULT_PRIORITY);
+    scheduler.enqueue(2, MAX_PRIORITY);
+    assert!(scheduler.tick(0, DEFAULT_PRIORITY));
+    assert_eq!(scheduler.pick_next(), Some(1));
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert_eq!(scheduler.pick_next(), None);
+}
+
+#[test_case]
+fn test_fixed_priority_preemption() {
+    let mut scheduler = FixedPriority::new();
+    scheduler.enqueue(1, 1);
+    assert!(!scheduler.tick(0, 2));
+    scheduler.enqueue(2, 5);
+    assert!(scheduler.tick(0, 2));
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert_eq!(scheduler.pick_next(), Some(1));
+}
+
+#[test_case]
+fn test_priority_runs_before_busy_thread() {
+    use crate::kernel::{join, launch, set_priority, sleep};
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static BUSY_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static BUSY_STOP: AtomicBool = AtomicBool::new(false);
+    static URGENT_SAW: AtomicUsize = AtomicUsize::new(0);
+
+    fn busy() {
+        while !BUSY_STOP.load(Ordering::Relaxed) {
+            BUSY_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn urgent() {
+        URGENT_SAW.store(BUSY_COUNT.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    let busy = launch(busy).unwrap();
+    set_priority(busy, 0).unwrap();
+    // the only thread left to run
+    sleep(1);
+    let before = BUSY_COUNT.load(Ordering::Relaxed);
+    assert!(before > 0);
+    let urgent = launch(urgent).unwrap();
+    set_priority(urgent, MAX_PRIORITY).unwrap();
+    join(urgent);
+    // the busy thread did not get the cpu in between
+    assert_eq!(URGENT_SAW.load(Ordering::Relaxed), before);
+    BUSY_STOP.store(true, Ordering::Relaxed);
+    join(busy);
+}
+
+#[test_case]
+fn test_mlfq_demotion() {
+    let mut scheduler = Mlfq::new();
+    scheduler.enqueue(1, DEFAULT_PRIORITY);
+    scheduler.enqueue(2, DEFAULT_PRIORITY);
+    assert_eq!(scheduler.pick_next(), Some(1));
+    // thread 1 uses its whole quantum and goes down a level
+    assert!(scheduler.tick(1, DEFAULT_PRIORITY));
+    scheduler.enqueue(1, DEFAULT_PRIORITY);
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert!(scheduler.tick(2, DEFAULT_PRIORITY));
+    scheduler.enqueue(2, DEFAULT_PRIORITY);
+    // both on level 1 now, with a quantum of 2 ticks
+    assert_eq!(scheduler.pick_next(), Some(1));
+    assert!(!scheduler.tick(1, DEFAULT_PRIORITY));
+}
@@ -0,0 +1,91 @@
+//! Thread stacks.
+//!
+//! They are mapped in ranges of the regions rather than taken from the heap,
+//! each with a guard page below: the guard is not mapped, so running past the end of
+//! a stack page faults instead of overwriting whatever lies below.
+//!
+//! The stacks of the ring 3 threads are user accessible, the kernel stacks are not.
+//! They live in the user stacks region, out of the processes' reach.
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::system::regions::{self, Region};
+use crate::system::{frames, paging};
+
+const PAGE_SIZE: usize = 4096;
+
+/// a stack and its guard page, unmapped and given back to the region when dropped
+pub struct Stack {
+    region: Region,
+    guard: Page,
+    // including the guard
+    pages: usize,
+    // mapped so far, the pages above the guard
+    mapped: usize,
+}
+
+impl Stack {
+    /// allocates a stack of at least `size` bytes, None if out of memory
+    ///
+    /// safety: must be called in a critical section
+    pub unsafe fn new(size: usize) -> Option<Self> {
+        Self::with_flags(Region::Kernel, size, PageTableFlags::WRITABLE)
+    }
+
+    /// same as `new`, for a stack ring 3 can use
+    ///
+    /// safety: must be called in a critical section
+    pub unsafe fn new_user(size: usize) -> Option<Self> {
+        Self::with_flags(
+            Region::UserStacks,
+            size,
+            PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        )
+    }
+
+    unsafe fn with_flags(region: Region, size: usize, flags: PageTableFlags) -> Option<Self> {
+        let pages = size.div_ceil(PAGE_SIZE) + 1;
+        let mut stack = Self {
+            region,
+            guard: regions::allocate(region, pages as u64)?,
+            pages,
+            mapped: 0,
+        };
+        // dropping the stack unmaps what was mapped
+        while stack.mapped < pages - 1 {
+            let page = stack.guard + 1 + stack.mapped as u64;
+            let frame = frames::allocate()?;
+            if paging::map(page, frame, flags).is_err() {
+                frames::deallocate(frame);
+                return None;
+            }
+            stack.mapped += 1;
+            // whatever the frame held before
+            core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, PAGE_SIZE);
+        }
+        Some(stack)
+    }
+
+    /// address past the end of the stack, page aligned
+    pub fn end(&self) -> usize {
+        (self.guard + self.pages as u64).start_address().as_u64() as usize
+    }
+
+    pub fn guard_contains(&self, addr: VirtAddr) -> bool {
+        Page::containing_address(addr) == self.guard
+    }
+}
+
+// must happen in a critical section too, the kernel drops stacks in `switch_stack_frame`
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            for index in 1..=self.mapped as u64 {
+                let frame = paging::unmap(self.guard + index).expect("stack page not mapped");
+                frames::deallocate(frame);
+            }
+            regions::free(self.region, self.guard, self.pages as u64);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Blocking synchronization primitives.
+//!
+//! Unlike `spin::Mutex`, a thread that has to wait is taken off the cpu
+//! (see `kernel::wait_on`) until the owner wakes it up, instead of spinning
+//! through its whole time slice.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{wait_on, wake};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// locked, and some threads may be waiting for the unlock
+const CONTENDED: u32 = 2;
+
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // we don't know how many threads are waiting, so we stay in the CONTENDED state
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                wait_on(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            wake(&self.state, 1);
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+pub struct Semaphore {
+    count: AtomicU32,
+}
+
+impl Semaphore {
+    pub const fn new(count: u32) -> Self {
+        Self {
+            count: AtomicU32::new(count),
+        }
+    }
+
+    /// takes one unit, waits until one is available
+    pub fn acquire(&self) {
+        loop {
+            let count = self.count.load(Ordering::Relaxed);
+            if count == 0 {
+                wait_on(&self.count, 0);
+            } else if self
+                .count
+                .compare_exchange(count, count - 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let count = self.count.load(Ordering::Relaxed);
+        count > 0
+            && self
+                .count
+                .compare_exchange(count, count - 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    /// gives one unit back
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        wake(&self.count, 1);
+    }
+}
+
+pub struct CondVar {
+    // bumped by every notification, so a waiter can't miss one
+    sequence: AtomicU32,
+}
+
+impl CondVar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// unlocks the mutex and waits for a notification, then locks it again
+    ///
+    /// may return spuriously, the condition has to be checked in a loop
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        drop(guard);
+        wait_on(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// waits until `condition` returns false
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        wake(&self.sequence, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        wake(&self.sequence, usize::MAX);
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Barrier {
+    count: u32,
+    arrived: AtomicU32,
+    // bumped each time the barrier opens
+    generation: AtomicU32,
+}
+
+impl Barrier {
+    /// a barrier that opens once `count` threads are waiting on it
+    pub const fn new(count: u32) -> Self {
+        Self {
+            count,
+            arrived: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    /// waits for the other threads, returns true in exactly one of them (the last one to arrive)
+    pub fn wait(&self) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.count {
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            wake(&self.generation, usize::MAX);
+            return true;
+        }
+        while self.generation.load(Ordering::Acquire) == generation {
+            wait_on(&self.generation, generation);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{exit, join, launch, launch_with, sleep, yield_now};
+    use crate::system::slab;
+
+    const WORKERS: usize = 4;
+
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+
+    fn mutex_worker() {
+        for i in 0..200 {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            if i % 16 == 0 {
+                // give the cpu away with the lock held, so that the others block on it
+                yield_now();
+            }
+            *counter = value + 1;
+        }
+    }
+
+    #[test_case]
+    fn test_mutex_contention() {
+        let ids = [(); WORKERS].map(|_| launch(mutex_worker).unwrap());
+        for id in ids {
+            join(id);
+        }
+        assert_eq!(*COUNTER.lock(), WORKERS * 200);
+    }
+
+    static ITEMS: Semaphore = Semaphore::new(0);
+
+    fn semaphore_producer() {
+        for _ in 0..10 {
+            ITEMS.release();
+            yield_now();
+        }
+    }
+
+    #[test_case]
+    fn test_semaphore_contention() {
+        let ids = [(); WORKERS].map(|_| launch(semaphore_producer).unwrap());
+        for _ in 0..WORKERS * 10 {
+            ITEMS.acquire();
+        }
+        assert!(!ITEMS.try_acquire());
+        for id in ids {
+            join(id);
+        }
+    }
+
+    static READY: Mutex<usize> = Mutex::new(0);
+    static READY_CHANGED: CondVar = CondVar::new();
+
+    fn condvar_notifier() {
+        yield_now();
+        *READY.lock() += 1;
+        READY_CHANGED.notify_all();
+    }
+
+    #[test_case]
+    fn test_condvar_wakes_waiter() {
+        let ids = [(); WORKERS].map(|_| launch(condvar_notifier).unwrap());
+        let ready = READY_CHANGED.wait_while(READY.lock(), |ready| *ready < WORKERS);
+        assert_eq!(*ready, WORKERS);
+        drop(ready);
+        for id in ids {
+            join(id);
+        }
+    }
+
+    static BARRIER: Barrier = Barrier::new(WORKERS as u32);
+    static ARRIVED: Mutex<usize> = Mutex::new(0);
+    static LEADERS: Mutex<usize> = Mutex::new(0);
+
+    fn barrier_worker() {
+        *ARRIVED.lock() += 1;
+        let leader = BARRIER.wait();
+        // nobody gets past the barrier before everyone reached it
+        assert_eq!(*ARRIVED.lock(), WORKERS);
+        if leader {
+            *LEADERS.lock() += 1;
+        }
+    }
+
+    #[test_case]
+    fn test_barrier_waits_for_everyone() {
+        let ids = [(); WORKERS].map(|_| launch(barrier_worker).unwrap());
+        for id in ids {
+            join(id);
+        }
+        assert_eq!(*LEADERS.lock(), 1);
+    }
+
+    static ORDER_WORD: AtomicU32 = AtomicU32::new(0);
+    // the waiters in the order they were woken up, one digit each
+    static WOKEN: Mutex<usize> = Mutex::new(0);
+
+    #[test_case]
+    fn test_waiters_wake_in_order() {
+        let ids = [1, 2, 3].map(|digit| {
+            let id = launch_with(move || {
+                wait_on(&ORDER_WORD, 0);
+                let mut woken = WOKEN.lock();
+                *woken = *woken * 10 + digit;
+                drop(woken);
+                exit(0)
+            })
+            .unwrap();
+            // in the wait queue before the next one
+            sleep(1);
+            id
+        });
+        for _ in ids {
+            assert_eq!(wake(&ORDER_WORD, 1), 1);
+        }
+        for id in ids {
+            join(id);
+        }
+        assert_eq!(*WOKEN.lock(), 123);
+        let nodes = slab::all_stats()
+            .into_iter()
+            .find(|stats| stats.name == "wait node");
+        assert_eq!(nodes.unwrap().in_use, 0);
+    }
+}
@@ -0,0 +1,493 @@
+//! The syscall table.
+//!
+//! A syscall takes its id in rax and up to six arguments in rdi, rsi, rdx, r10, r8
+//! and r9 (r10 rather than rcx, which `syscall` overwrites). Its result comes back in
+//! rax: a value, or an error as a negative `Errno`, like on Linux.
+//!
+//! Every syscall is declared once in `syscalls!` below, which generates its id, its
+//! entry in the table and its wrappers: `int` for `int 0x80`, from ring 0 or ring 3,
+//! and `fast` for `syscall`, from ring 3 only. The wrappers are inlined, ring 3 code
+//! can use them.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::elf;
+use super::process::{self, Pid};
+use super::scheduler::Priority;
+use super::{ExitCode, LaunchSpec, StackFrame, ThreadState, STATE};
+
+/// errors are the values above `usize::MAX - MAX_ERRNO` in rax
+pub const MAX_ERRNO: usize = 4095;
+
+pub type SyscallResult = Result<usize, Errno>;
+
+/// errors returned by the syscalls, negated in rax
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// no syscall with this id
+    NoSuchSyscall = 1,
+    /// the syscall is not allowed in ring 3
+    PermissionDenied = 2,
+    NoSuchThread = 3,
+    /// the thread has not finished yet (`try_join`)
+    WouldBlock = 4,
+    /// `set_max_threads` reached
+    TooManyThreads = 5,
+    /// no room left for a stack, or for the memory of a process
+    OutOfMemory = 6,
+    /// or the caller is not in a process
+    NoSuchProcess = 7,
+    InvalidArgument = 8,
+    /// not a program the ELF loader accepts
+    InvalidExecutable = 9,
+    /// the arguments of a program don't fit on its stack
+    ArgumentsTooLong = 10,
+}
+
+impl Errno {
+    #[inline(always)]
+    fn from_raw(errno: usize) -> Self {
+        match errno {
+            2 => Self::PermissionDenied,
+            3 => Self::NoSuchThread,
+            4 => Self::WouldBlock,
+            5 => Self::TooManyThreads,
+            6 => Self::OutOfMemory,
+            7 => Self::NoSuchProcess,
+            8 => Self::InvalidArgument,
+            9 => Self::InvalidExecutable,
+            10 => Self::ArgumentsTooLong,
+            // the kernel returns no other value
+            _ => Self::NoSuchSyscall,
+        }
+    }
+}
+
+/// the value of rax after a syscall
+#[inline(always)]
+pub fn decode(ret: usize) -> SyscallResult {
+    if ret > usize::MAX - MAX_ERRNO {
+        Err(Errno::from_raw(ret.wrapping_neg()))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn encode(result: SyscallResult) -> usize {
+    match result {
+        Ok(value) => {
+            debug_assert!(
+                value <= usize::MAX - MAX_ERRNO,
+                "syscall value reads as an error"
+            );
+            value
+        }
+        Err(errno) => (errno as usize).wrapping_neg(),
+    }
+}
+
+/// a type passed in a register
+pub trait SyscallArg {
+    fn into_arg(self) -> usize;
+    fn from_arg(arg: usize) -> Self;
+}
+
+impl SyscallArg for usize {
+    #[inline(always)]
+    fn into_arg(self) -> usize {
+        self
+    }
+    #[inline(always)]
+    fn from_arg(arg: usize) -> Self {
+        arg
+    }
+}
+
+impl SyscallArg for u32 {
+    #[inline(always)]
+    fn into_arg(self) -> usize {
+        self as usize
+    }
+    #[inline(always)]
+    fn from_arg(arg: usize) -> Self {
+        arg as u32
+    }
+}
+
+impl SyscallArg for u8 {
+    #[inline(always)]
+    fn into_arg(self) -> usize {
+        self as usize
+    }
+    #[inline(always)]
+    fn from_arg(arg: usize) -> Self {
+        arg as u8
+    }
+}
+
+impl<T> SyscallArg for *const T {
+    #[inline(always)]
+    fn into_arg(self) -> usize {
+        self as usize
+    }
+    #[inline(always)]
+    fn from_arg(arg: usize) -> Self {
+        arg as *const T
+    }
+}
+
+/// who may make a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// ring 0 only: the syscall takes pointers to kernel memory or manages kernel threads
+    Kernel,
+    /// ring 3 too
+    User,
+}
+
+/// `caller`, its stack frame and the six argument registers
+type Handler = fn(usize, &StackFrame, &[usize; 6]) -> SyscallResult;
+
+struct Entry {
+    syscall: Syscall,
+    name: &'static str,
+    arg_names: &'static [&'static str],
+    access: Access,
+    handler: Handler,
+}
+
+// fills the unused argument registers with 0
+macro_rules! args {
+    () => {
+        [0, 0, 0, 0, 0, 0]
+    };
+    ($a:expr) => {
+        [$a, 0, 0, 0, 0, 0]
+    };
+    ($a:expr, $b:expr) => {
+        [$a, $b, 0, 0, 0, 0]
+    };
+    ($a:expr, $b:expr, $c:expr) => {
+        [$a, $b, $c, 0, 0, 0]
+    };
+    ($a:expr, $b:expr, $c:expr, $d:expr) => {
+        [$a, $b, $c, $d, 0, 0]
+    };
+    ($a:expr, $b:expr, $c:expr, $d:expr, $e:expr) => {
+        [$a, $b, $c, $d, $e, 0]
+    };
+    ($a:expr, $b:expr, $c:expr, $d:expr, $e:expr, $f:expr) => {
+        [$a, $b, $c, $d, $e, $f]
+    };
+}
+
+macro_rules! wrapper {
+    ($raw:ident, $name:ident, $(#[$doc:meta])* $wrapper:ident($($arg:ident: $ty:ty),*)) => {
+        $(#[$doc])*
+        #[inline(always)]
+        pub fn $wrapper($($arg: $ty),*) -> SyscallResult {
+            decode(unsafe { $raw(Syscall::$name, args!($(SyscallArg::into_arg($arg)),*)) })
+        }
+    };
+}
+
+macro_rules! syscalls {
+    ($(
+        $(#[$doc:meta])*
+        $name:ident = $id:literal, $access:ident => fn $wrapper:ident($($arg:ident: $ty:ty),*) = $handler:ident;
+    )*) => {
+        #[repr(usize)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Syscall {
+            $($name = $id,)*
+        }
+
+        // indexed by id
+        static TABLE: &[Entry] = &[$(
+            Entry {
+                syscall: Syscall::$name,
+                name: stringify!($wrapper),
+                arg_names: &[$(stringify!($arg)),*],
+                access: Access::$access,
+                handler: |caller, stack_frame, args| {
+                    let mut _args = args.iter().copied();
+                    $handler(caller, stack_frame, $(<$ty as SyscallArg>::from_arg(_args.next().unwrap())),*)
+                },
+            },
+        )*];
+
+        /// the syscalls through `int 0x80`
+        pub mod int {
+            use super::*;
+            $(wrapper!(raw_int, $name, $(#[$doc])* $wrapper($($arg: $ty),*));)*
+        }
+
+        /// the syscalls through `syscall`, ring 3 only
+        pub mod fast {
+            use super::*;
+            $(wrapper!(raw_syscall, $name, $(#[$doc])* $wrapper($($arg: $ty),*));)*
+        }
+    };
+}
+
+syscalls! {
+    /// launches a thread, returns its id
+    LaunchThread = 0, Kernel => fn launch_thread(spec: *const LaunchSpec) = sys_launch_thread;
+    /// terminates the calling thread, does not return
+    Exit = 1, User => fn exit(code: ExitCode) = sys_exit;
+    /// waits for a thread to finish, returns its exit code
+    Join = 2, Kernel => fn join(id: usize) = sys_join;
+    /// returns the exit code of a thread, `WouldBlock` if it is still running
+    TryJoin = 3, Kernel => fn try_join(id: usize) = sys_try_join;
+    Yield = 4, User => fn yield_now() = sys_yield;
+    /// returns after at least `ticks` timer ticks
+    Sleep = 5, User => fn sleep(ticks: usize) = sys_sleep;
+    SetPriority = 6, Kernel => fn set_priority(id: usize, priority: Priority) = sys_set_priority;
+    /// returns 1 if the caller went to sleep, 0 if the word no longer contains `expected`
+    Wait = 7, Kernel => fn wait(word: *const AtomicU32, expected: u32) = sys_wait;
+    /// returns the number of threads woken up
+    Wake = 8, Kernel => fn wake(word: *const AtomicU32, count: usize) = sys_wake;
+    /// starts a process running `entry`, which must be in `user_text`, returns its pid
+    SpawnProcess = 9, Kernel => fn spawn_process(entry: usize, name_ptr: *const u8, name_len: usize) = sys_spawn_process;
+    /// waits for a process to end, returns its exit code
+    WaitProcess = 10, Kernel => fn wait_process(pid: Pid) = sys_wait_process;
+    /// starts a thread running `entry` in the caller's process, returns its id
+    SpawnThread = 11, User => fn spawn_thread(entry: usize) = sys_spawn_thread;
+    /// grows the heap of the caller's process, returns the address of the new memory
+    GrowHeap = 12, User => fn grow_heap(bytes: usize) = sys_grow_heap;
+    /// loads an ELF program in a new process and starts it with `args` and `env`,
+    /// returns its pid
+    SpawnElf = 13, Kernel => fn spawn_elf(image: *const u8, image_len: usize, args: *const &str, args_len: usize, env: *const &str, env_len: usize) = sys_spawn_elf;
+}
+
+#[inline(always)]
+unsafe fn raw_int(syscall: Syscall, args: [usize; 6]) -> usize {
+    let [rdi, rsi, rdx, r10, r8, r9] = args;
+    let ret: usize;
+    core::arch::asm!(
+        "int 0x80",
+        inlateout("rax") syscall as usize => ret,
+        in("rdi") rdi,
+        in("rsi") rsi,
+        in("rdx") rdx,
+        in("r10") r10,
+        in("r8") r8,
+        in("r9") r9,
+    );
+    ret
+}
+
+/// same as `raw_int`, rcx and r11 are clobbered
+#[inline(always)]
+unsafe fn raw_syscall(syscall: Syscall, args: [usize; 6]) -> usize {
+    let [rdi, rsi, rdx, r10, r8, r9] = args;
+    let ret: usize;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") syscall as usize => ret,
+        in("rdi") rdi,
+        in("rsi") rsi,
+        in("rdx") rdx,
+        in("r10") r10,
+        in("r8") r8,
+        in("r9") r9,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    ret
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// logs every syscall on the serial port, or stops
+pub fn set_tracing(tracing: bool) {
+    TRACING.store(tracing, Ordering::Relaxed);
+}
+
+/// runs the syscall of `caller`, whose registers are saved, and puts the result in its rax
+///
+/// safety: must be called in a critical section
+pub(super) unsafe fn dispatch(caller: usize, stack_frame: &StackFrame) {
+    let regs = STATE.thread(caller).cpu_regs;
+    let id = regs.rax as usize;
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9].map(|reg| reg as usize);
+    let from_user = stack_frame.code_segment & 3 == 3;
+
+    // a gap in the ids would shift the entries after it
+    let entry = TABLE.get(id).filter(|entry| entry.syscall as usize == id);
+    let result = match entry {
+        None => Err(Errno::NoSuchSyscall),
+        Some(entry) if from_user && entry.access == Access::Kernel => {
+            crate::sprintln!("thread {}: {} not allowed in ring 3", caller, entry.name);
+            Err(Errno::PermissionDenied)
+        }
+        Some(entry) => (entry.handler)(caller, stack_frame, &args),
+    };
+    if TRACING.load(Ordering::Relaxed) {
+        trace(caller, id, entry, &args, result);
+    }
+    // a blocked caller gets its result later, from whoever wakes it up
+    STATE.thread(caller).cpu_regs.rax = encode(result) as u64;
+}
+
+fn trace(
+    caller: usize,
+    id: usize,
+    entry: Option<&Entry>,
+    args: &[usize; 6],
+    result: SyscallResult,
+) {
+    let entry = match entry {
+        Some(entry) => entry,
+        None => {
+            crate::sprintln!("thread {}: syscall {:#x} = {:?}", caller, id, result);
+            return;
+        }
+    };
+    crate::sprint!("thread {}: {}(", caller, entry.name);
+    for (n, (name, value)) in entry.arg_names.iter().zip(args).enumerate() {
+        if n > 0 {
+            crate::sprint!(", ");
+        }
+        crate::sprint!("{}: {:#x}", name, value);
+    }
+    crate::sprintln!(") = {:?}", result);
+}
+
+//
+// handlers, called with the caller's registers saved:
+// one that takes it off the cpu just has to change its state
+//
+
+fn sys_launch_thread(_: usize, stack_frame: &StackFrame, spec: *const LaunchSpec) -> SyscallResult {
+    unsafe { super::launch_thread(&*spec, stack_frame) }
+}
+
+fn sys_exit(caller: usize, _: &StackFrame, code: ExitCode) -> SyscallResult {
+    // `join` must not read it as an error
+    unsafe { super::exit_thread(caller, code.min(super::MAX_EXIT_CODE)) };
+    Ok(0)
+}
+
+fn sys_join(caller: usize, _: &StackFrame, id: usize) -> SyscallResult {
+    unsafe { super::join_thread(caller, id, true) }
+}
+
+fn sys_try_join(caller: usize, _: &StackFrame, id: usize) -> SyscallResult {
+    unsafe { super::join_thread(caller, id, false) }
+}
+
+fn sys_yield(caller: usize, _: &StackFrame) -> SyscallResult {
+    unsafe { STATE.make_ready(caller) };
+    Ok(0)
+}
+
+fn sys_sleep(caller: usize, _: &StackFrame, ticks: usize) -> SyscallResult {
+    unsafe {
+        STATE.thread(caller).state = ThreadState::Sleeping(STATE.ticks.saturating_add(ticks))
+    };
+    Ok(0)
+}
+
+fn sys_set_priority(_: usize, _: &StackFrame, id: usize, priority: Priority) -> SyscallResult {
+    unsafe { super::set_thread_priority(id, priority) }
+}
+
+fn sys_wait(caller: usize, _: &StackFrame, word: *const AtomicU32, expected: u32) -> SyscallResult {
+    unsafe { super::wait_on_word(caller, word as usize, expected) }
+}
+
+fn sys_wake(_: usize, _: &StackFrame, word: *const AtomicU32, count: usize) -> SyscallResult {
+    unsafe { super::wake_word(word as usize, count) }
+}
+
+fn sys_spawn_process(
+    _: usize,
+    stack_frame: &StackFrame,
+    entry: usize,
+    name_ptr: *const u8,
+    name_len: usize,
+) -> SyscallResult {
+    unsafe {
+        let name = core::slice::from_raw_parts(name_ptr, name_len);
+        process::spawn(
+            entry,
+            core::str::from_utf8(name).unwrap_or("?"),
+            stack_frame,
+        )
+    }
+}
+
+fn sys_wait_process(caller: usize, _: &StackFrame, pid: Pid) -> SyscallResult {
+    unsafe { process::wait(caller, pid) }
+}
+
+fn sys_spawn_thread(caller: usize, stack_frame: &StackFrame, entry: usize) -> SyscallResult {
+    unsafe { process::spawn_thread(caller, entry, stack_frame) }
+}
+
+fn sys_grow_heap(caller: usize, _: &StackFrame, bytes: usize) -> SyscallResult {
+    unsafe { process::grow_heap(caller, bytes) }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sys_spawn_elf(
+    _: usize,
+    stack_frame: &StackFrame,
+    image: *const u8,
+    image_len: usize,
+    args: *const &str,
+    args_len: usize,
+    env: *const &str,
+    env_len: usize,
+) -> SyscallResult {
+    unsafe {
+        elf::spawn(
+            core::slice::from_raw_parts(image, image_len),
+            core::slice::from_raw_parts(args, args_len),
+            core::slice::from_raw_parts(env, env_len),
+            stack_frame,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_syscall_table_is_indexed_by_id() {
+        for (id, entry) in TABLE.iter().enumerate() {
+            assert_eq!(entry.syscall as usize, id);
+        }
+    }
+
+    #[test_case]
+    fn test_errors_round_trip() {
+        for errno in [
+            Errno::PermissionDenied,
+            Errno::NoSuchThread,
+            Errno::OutOfMemory,
+        ] {
+            assert_eq!(decode(encode(Err(errno))), Err(errno));
+        }
+        assert_eq!(
+            decode(encode(Ok(usize::MAX - MAX_ERRNO))),
+            Ok(usize::MAX - MAX_ERRNO)
+        );
+    }
+
+    #[test_case]
+    fn test_unknown_syscall() {
+        let ret = unsafe { raw_int_id(TABLE.len(), 0) };
+        assert_eq!(decode(ret), Err(Errno::NoSuchSyscall));
+    }
+
+    /// an id `Syscall` does not have
+    unsafe fn raw_int_id(id: usize, arg: usize) -> usize {
+        let ret: usize;
+        core::arch::asm!("int 0x80", inlateout("rax") id => ret, in("rdi") arg);
+        ret
+    }
+}
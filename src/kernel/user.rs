@@ -0,0 +1,237 @@
+//! Threads running in ring 3.
+//!
+//! Their code lives in the `user_text` section (`#[link_section = "user_text"]`),
+//! which is made user accessible along with their stacks, and nothing else: a user
+//! thread touching any other memory faults and gets killed. It may only call the
+//! functions of that section, and the inlined syscall wrappers: those of this module
+//! and of `syscall::fast` use the `syscall` instruction, the `int 0x80` ones of
+//! `kernel` work too. The syscalls marked `Access::Kernel` fail with `PermissionDenied`.
+//!
+//! A ring 3 thread runs in the kernel address space, unless it belongs to a process,
+//! see `process`.
+
+use core::ptr::addr_of;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use super::syscall::{fast, Errno};
+use super::ExitCode;
+use crate::system::{gdt, paging};
+
+/// code run by a ring 3 thread, returning from it exits with code 0
+pub type UserEntry = extern "C" fn();
+
+extern "C" {
+    // defined by the linker around the `user_text` section
+    static __start_user_text: u8;
+    static __stop_user_text: u8;
+}
+
+/// makes the `user_text` section accessible from ring 3, and enables `syscall`
+///
+/// the pages at both ends may hold some kernel code as well,
+/// ring 3 can read and run it but not modify it
+///
+/// safety: must be called once, after `paging::init`
+pub unsafe fn init() {
+    // `syscall` jumps to `syscall_entry` with the kernel segments,
+    // `sysretq` goes back with the user ones
+    Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("the GDT is not in the order sysretq expects");
+    LStar::write(VirtAddr::new(super::syscall_entry as usize as u64));
+    // cleared by `syscall`, no interrupt until `syscall_entry` is on the kernel stack
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+
+    let start = VirtAddr::from_ptr(addr_of!(__start_user_text));
+    let end = VirtAddr::from_ptr(addr_of!(__stop_user_text));
+    let pages = Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(end - 1u64),
+    );
+    for page in pages {
+        paging::set_user_accessible(page, true);
+    }
+}
+
+/// whether `addr` is in the `user_text` section
+pub(super) fn in_user_text(addr: usize) -> bool {
+    let start = unsafe { addr_of!(__start_user_text) } as usize;
+    let end = unsafe { addr_of!(__stop_user_text) } as usize;
+    (start..end).contains(&addr)
+}
+
+/// where a `UserEntry` returns, its address is at the top of the user stack
+#[link_section = "user_text"]
+pub(super) extern "C" fn user_return() {
+    exit(0);
+}
+
+/// `kernel::exit` for ring 3
+#[inline(always)]
+pub fn exit(code: ExitCode) -> ! {
+    let _ = fast::exit(code);
+    unreachable!("exit returned");
+}
+
+/// `kernel::yield_now` for ring 3
+#[inline(always)]
+pub fn yield_now() {
+    let _ = fast::yield_now();
+}
+
+/// `kernel::sleep` for ring 3
+#[inline(always)]
+pub fn sleep(ticks: usize) {
+    let _ = fast::sleep(ticks);
+}
+
+/// starts a thread running `entry` in the process of the caller, returns its id
+#[inline(always)]
+pub fn spawn_thread(entry: UserEntry) -> Result<usize, Errno> {
+    fast::spawn_thread(entry as usize)
+}
+
+/// maps at least `bytes` more at the end of the heap of the caller's process,
+/// returns their address
+#[inline(always)]
+pub fn grow_heap(bytes: usize) -> Result<*mut u8, Errno> {
+    // no `Result::map`, which may not be inlined
+    match fast::grow_heap(bytes) {
+        Ok(addr) => Ok(addr as *mut u8),
+        Err(errno) => Err(errno),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{join, launch_user, spawn_process, wait_process, Errno, FAULT_EXIT_CODE};
+    use core::ptr::addr_of_mut;
+
+    #[link_section = "user_text"]
+    extern "C" fn user_sum() {
+        let mut sum: usize = 0;
+        let mut i: usize = 1;
+        while i <= 10 {
+            sum = sum.wrapping_add(i);
+            i = i.wrapping_add(1);
+        }
+        exit(sum);
+    }
+
+    #[test_case]
+    fn test_user_thread_exits() {
+        let id = launch_user(user_sum).unwrap();
+        assert_eq!(join(id), 55);
+    }
+
+    static mut KERNEL_DATA: usize = 0;
+
+    #[link_section = "user_text"]
+    extern "C" fn user_scribble() {
+        unsafe { *addr_of_mut!(KERNEL_DATA) = 42 };
+    }
+
+    #[test_case]
+    fn test_user_thread_cannot_write_kernel_memory() {
+        let id = launch_user(user_scribble).unwrap();
+        assert_eq!(join(id), FAULT_EXIT_CODE);
+        assert_eq!(unsafe { *addr_of!(KERNEL_DATA) }, 0);
+    }
+
+    /// exits with 1 if joining thread 0 is refused
+    #[link_section = "user_text"]
+    extern "C" fn user_join() {
+        let refused = matches!(fast::join(0), Err(Errno::PermissionDenied));
+        exit(refused as usize);
+    }
+
+    #[test_case]
+    fn test_user_thread_cannot_use_kernel_syscalls() {
+        assert_eq!(join(launch_user(user_join).unwrap()), 1);
+    }
+
+    /// waking up takes the whole process down
+    #[link_section = "user_text"]
+    extern "C" fn user_sleep_forever() {
+        sleep(usize::MAX);
+        unsafe { core::arch::asm!("ud2") };
+    }
+
+    /// leaves a thread asleep for good, then ends the process, and that thread with it
+    #[link_section = "user_text"]
+    extern "C" fn user_leave_sleeper() {
+        if spawn_thread(user_sleep_forever).is_err() {
+            exit(1);
+        }
+        // the wake up tick saturates, it neither overflows nor wraps to now
+        sleep(3);
+        exit(7);
+    }
+
+    #[test_case]
+    fn test_user_thread_sleeps_forever() {
+        let pid = spawn_process("sleeper", user_leave_sleeper).unwrap();
+        assert_eq!(wait_process(pid), 7);
+    }
+
+    const ROUND_TRIPS: usize = 1000;
+
+    #[inline(always)]
+    fn rdtsc() -> usize {
+        let low: u32;
+        let high: u32;
+        unsafe { core::arch::asm!("rdtsc", out("eax") low, out("edx") high) };
+        ((high as usize) << 32) | low as usize
+    }
+
+    /// exits with the number of cycles taken by `ROUND_TRIPS` yields through `int 0x80`
+    #[link_section = "user_text"]
+    extern "C" fn yield_through_interrupt_gate() {
+        let start = rdtsc();
+        let mut i: usize = 0;
+        while i < ROUND_TRIPS {
+            crate::kernel::yield_now();
+            i = i.wrapping_add(1);
+        }
+        exit(rdtsc().wrapping_sub(start));
+    }
+
+    /// same through `syscall`
+    #[link_section = "user_text"]
+    extern "C" fn yield_through_syscall() {
+        let start = rdtsc();
+        let mut i: usize = 0;
+        while i < ROUND_TRIPS {
+            yield_now();
+            i = i.wrapping_add(1);
+        }
+        exit(rdtsc().wrapping_sub(start));
+    }
+
+    #[test_case]
+    fn bench_syscall_against_interrupt_gate() {
+        // alone in the scheduler queues, each yield comes back to the same thread
+        let interrupt_gate = join(launch_user(yield_through_interrupt_gate).unwrap());
+        let syscall = join(launch_user(yield_through_syscall).unwrap());
+        crate::sprint!(
+            "(int 0x80: {} cycles, syscall: {} cycles per round trip) ",
+            interrupt_gate / ROUND_TRIPS,
+            syscall / ROUND_TRIPS
+        );
+    }
+}
@@ -1,11 +1,13 @@
 #![no_std]
 #![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![feature(naked_functions)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::BootInfo;
 use core::arch::asm;
 
 extern crate alloc;
@@ -21,12 +23,19 @@ pub unsafe fn port_long_out(port: u16, value: u32) {
     asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
 }
 
-pub fn init() {
+pub fn init(boot_info: &'static BootInfo) {
     system::idt::init();
     system::gdt::init();
-    unsafe { system::idt::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
+    system::paging::init(x86_64::VirtAddr::new(boot_info.physical_memory_offset));
+    unsafe {
+        system::frames::init(&boot_info.memory_map);
+        system::memory::init();
+        system::regions::init();
+    }
+    unsafe { system::interrupts::init() };
+    // the timer handler needs thread 0 and the scheduler
     kernel::init();
+    x86_64::instructions::interrupts::enable();
     drivers::vga::init()
 }
 
@@ -72,15 +81,18 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     sprintln!("[failed]\n");
     sprintln!("Error: {}\n", info);
+    system::backtrace::print_current();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
 /// Entry point for `cargo test`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
     test_main();
     hlt_loop();
 }
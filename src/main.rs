@@ -6,17 +6,22 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
+use primoria::kernel::scheduler::INTERACTIVE_PRIORITY;
 use primoria::kprintln;
 
 extern crate alloc;
 
 use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use primoria::kernel::{channel, launch_with, spawn_process, wait_process, Builder};
+use vga::colors::Color16;
 
 mod apps;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    primoria::init();
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
     primoria::sprintln!("Primoria Start");
 
     unsafe {
@@ -32,18 +37,50 @@ fn main() {
     #[cfg(test)]
     test_main();
 
+    // this thread hosts the shell, ahead of the apps it starts
+    primoria::kernel::set_priority(primoria::kernel::thread_id(), INTERACTIVE_PRIORITY).unwrap();
+
     let heap_value_1 = Box::new(41);
     let heap_value_2 = Box::new(13);
     assert_eq!(*heap_value_1, 41);
     assert_eq!(*heap_value_2, 13);
 
-    let id1 = primoria::kernel::launch(apps::simple_counter_1);
-    let id2 = primoria::kernel::launch(apps::simple_counter_2);
+    let id1 = launch_with(|| apps::simple_counter(79, Color16::Green, false)).unwrap();
+    let id2 = Builder::new()
+        .name("waiting counter")
+        .spawn(|| apps::simple_counter(69, Color16::Blue, true))
+        .unwrap();
+    let (progress, countdown) = channel(1);
+    let id3 = Builder::new()
+        .name("countdown")
+        .spawn(move || apps::countdown(5, progress))
+        .unwrap();
+
+    // each in its own address space, the crash of the second one does not affect the first
+    let pid1 = spawn_process("user sum", apps::user_sum).unwrap();
+    let pid2 = spawn_process("user scribble", apps::user_scribble).unwrap();
+
+    primoria::sprintln!(
+        "I'm parent, children ids = {}, {}, {}, processes = {}, {}",
+        id1,
+        id2,
+        id3,
+        pid1,
+        pid2
+    );
+
+    while let Ok(remaining) = countdown.recv() {
+        primoria::sprintln!("countdown: {}", remaining);
+    }
+
+    for pid in [pid1, pid2] {
+        let code = wait_process(pid);
+        primoria::sprintln!("process {} exited with code {}", pid, code);
+    }
 
-    primoria::sprintln!("I'm parent, children ids = {}, {}", id1, id2);
-    loop {
-        // not much better to do, idk
-        core::hint::spin_loop();
+    for id in [id3, id1, id2] {
+        let code = primoria::kernel::join(id);
+        primoria::sprintln!("child {} exited with code {}", id, code);
     }
 }
 
@@ -52,6 +89,8 @@ fn main() {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     kprintln!("{}", info);
+    primoria::sprintln!("{}", info);
+    primoria::system::backtrace::print_current();
     primoria::hlt_loop();
 }
 
@@ -0,0 +1,167 @@
+//! The ACPI tables, for the interrupt controllers of the MADT.
+//!
+//! The RSDP is searched where the BIOS leaves it, in the first KiB of the EBDA or in
+//! 0xe0000..0x100000, then the RSDT, or the XSDT, lists the other tables. They are in
+//! RAM, read through the mapping of the physical memory, see `paging::phys_to_virt`.
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use super::paging;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const SDT_HEADER_SIZE: usize = 36;
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+// the kinds of MADT entries used
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// the interrupt controllers of the machine
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    /// the 8259 PICs are there too, to be disabled
+    pub has_pics: bool,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<SourceOverride>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// the first global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+/// an ISA interrupt that isn't wired to the I/O APIC pin of the same number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// polarity in bits 0-1, trigger mode in bits 2-3, 0 for the bus default
+    pub flags: u16,
+}
+
+/// `len` bytes of physical memory from `addr`
+///
+/// safety: the range must be mapped, and hold what is read there
+unsafe fn bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(paging::phys_to_virt(addr).as_ptr(), len)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// the physical address of the RSDP, None if the BIOS didn't leave one
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe { read_u16(bytes(PhysAddr::new(0x40e), 2), 0) } as u64 * 16;
+    let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+    areas
+        .into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, len)| (start..start + len).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            let rsdp = unsafe { bytes(addr, 20) };
+            rsdp[..8] == RSDP_SIGNATURE[..] && checksum_ok(rsdp)
+        })
+}
+
+/// the whole table whose header is at `addr`, None if its checksum is wrong
+///
+/// safety: `addr` must be that of an ACPI table
+unsafe fn table(addr: PhysAddr) -> Option<&'static [u8]> {
+    let len = read_u32(bytes(addr, SDT_HEADER_SIZE), 4) as usize;
+    let table = bytes(addr, len);
+    checksum_ok(table).then_some(table)
+}
+
+/// the table with `signature`, from the XSDT, or the RSDT before ACPI 2.0
+fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = unsafe { bytes(rsdp_addr, 36) };
+    let (root, entry_size) = if rsdp[15] >= 2 {
+        (read_u64(rsdp, 24), 8)
+    } else {
+        (read_u32(rsdp, 16) as u64, 4)
+    };
+    let root = unsafe { table(PhysAddr::new(root)) }?;
+    root[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .filter_map(|addr| unsafe { table(PhysAddr::new(addr)) })
+        .find(|table| table[..4] == signature[..])
+}
+
+/// the MADT, None without ACPI
+pub fn madt() -> Option<Madt> {
+    let table = find_table(MADT_SIGNATURE)?;
+    let mut madt = Madt {
+        local_apic: PhysAddr::new(read_u32(table, 36) as u64),
+        has_pics: read_u32(table, 40) & 1 != 0,
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= table.len() {
+        let (kind, len) = (table[offset], table[offset + 1] as usize);
+        if len < 2 || offset + len > table.len() {
+            break;
+        }
+        let entry = &table[offset..offset + len];
+        match kind {
+            IO_APIC => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            INTERRUPT_SOURCE_OVERRIDE => madt.overrides.push(SourceOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            LOCAL_APIC_ADDRESS_OVERRIDE => madt.local_apic = PhysAddr::new(read_u64(entry, 4)),
+            _ => {}
+        }
+        offset += len;
+    }
+    Some(madt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_madt_has_the_apics() {
+        let madt = madt().expect("no MADT");
+        assert_eq!(madt.local_apic, PhysAddr::new(0xfee0_0000));
+        assert_eq!(madt.io_apics.len(), 1);
+        assert_eq!(madt.io_apics[0].gsi_base, 0);
+        // the PIT is on pin 2 in QEMU
+        assert!(madt
+            .overrides
+            .iter()
+            .any(|entry| entry.irq == 0 && entry.gsi == 2));
+    }
+}
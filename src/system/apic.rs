@@ -0,0 +1,282 @@
+//! The local APIC of the cpu and the I/O APICs, from the MADT.
+//!
+//! The I/O APICs route the ISA interrupts of the devices to the local APIC, and the
+//! `interrupt source overrides` of the MADT tell which pin each one is wired to. The
+//! timer of the local APIC replaces the PIT, calibrated against it to keep the period
+//! the PIT had at its default divisor, so `kernel::ticks` still counts ~18.2 per second.
+//!
+//! The registers are memory mapped, uncached, in pages from `regions`.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::acpi::{Madt, SourceOverride};
+use super::interrupts::{InterruptController, InterruptIndex};
+use super::paging;
+use super::regions::{self, Region};
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// the registers of the local APIC
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS_VECTOR: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+// the registers of an I/O APIC, selected by IOREGSEL, accessed through IOWIN
+const IO_REGISTER_SELECT: usize = 0x00;
+const IO_WINDOW: usize = 0x10;
+const IO_VERSION: u32 = 0x01;
+const IO_REDIRECTION: u32 = 0x10;
+
+// in the redirection entries, with fixed delivery to a physical destination
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+// in the flags of the source overrides
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// the PIT counts down 0xffff in ~54.9 ms, its period when left at the default divisor
+const PIT_COUNTS: u16 = 0xffff;
+
+/// maps the page of registers at `addr`, None without a free page
+///
+/// safety: `addr` must be that of device registers
+unsafe fn map_registers(addr: PhysAddr) -> Option<VirtAddr> {
+    let page = regions::allocate(Region::Kernel, 1)?;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+    paging::map(page, PhysFrame::containing_address(addr), flags).ok()?;
+    Some(page.start_address() + (addr.as_u64() & 0xfff))
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        write_volatile((self.base + IO_REGISTER_SELECT).as_mut_ptr(), register);
+        read_volatile((self.base + IO_WINDOW).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        write_volatile((self.base + IO_REGISTER_SELECT).as_mut_ptr(), register);
+        write_volatile((self.base + IO_WINDOW).as_mut_ptr(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.pins).contains(&gsi)
+    }
+
+    #[cfg(test)]
+    fn entry(&self, pin: u32) -> u64 {
+        without_interrupts(|| unsafe {
+            let low = self.read(IO_REDIRECTION + 2 * pin) as u64;
+            let high = self.read(IO_REDIRECTION + 2 * pin + 1) as u64;
+            high << 32 | low
+        })
+    }
+
+    /// safety: the entry must send a vector with a handler
+    unsafe fn set_entry(&self, pin: u32, entry: u64) {
+        without_interrupts(|| {
+            // the mask is in the low half, the destination is set before it is unmasked
+            self.write(IO_REDIRECTION + 2 * pin + 1, (entry >> 32) as u32);
+            self.write(IO_REDIRECTION + 2 * pin, entry as u32);
+        })
+    }
+}
+
+pub struct Apic {
+    local: VirtAddr,
+    io_apics: Vec<IoApic>,
+    overrides: Vec<SourceOverride>,
+}
+
+impl Apic {
+    /// maps the registers of the APICs of `madt`, None without an I/O APIC
+    ///
+    /// safety: `madt` must describe the machine
+    pub unsafe fn new(madt: &Madt) -> Option<Self> {
+        if madt.io_apics.is_empty() {
+            return None;
+        }
+        let mut io_apics = Vec::new();
+        for info in &madt.io_apics {
+            let mut io_apic = IoApic {
+                base: map_registers(info.address)?,
+                gsi_base: info.gsi_base,
+                pins: 0,
+            };
+            // the highest entry in bits 16-23
+            io_apic.pins = (io_apic.read(IO_VERSION) >> 16 & 0xff) + 1;
+            io_apics.push(io_apic);
+        }
+        Some(Self {
+            local: map_registers(madt.local_apic)?,
+            io_apics,
+            overrides: madt.overrides.clone(),
+        })
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_volatile((self.local + register).as_ptr()) }
+    }
+
+    unsafe fn write(&self, register: usize, value: u32) {
+        write_volatile((self.local + register).as_mut_ptr(), value)
+    }
+
+    fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// enables the local APIC and its timer, routes the devices of `InterruptIndex`
+    /// to it and masks the other pins
+    ///
+    /// safety: must be called once, with the interrupts disabled and the PICs masked
+    pub unsafe fn init(&self) {
+        let mut base = Msr::new(APIC_BASE_MSR);
+        base.write(base.read() | APIC_GLOBAL_ENABLE);
+        self.write(
+            SPURIOUS_VECTOR,
+            SOFTWARE_ENABLE | InterruptIndex::Spurious.as_u8() as u32,
+        );
+        self.write(TASK_PRIORITY, 0);
+
+        let count = self.calibrate_timer();
+        self.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        self.write(
+            LVT_TIMER,
+            TIMER_PERIODIC | InterruptIndex::Timer.as_u8() as u32,
+        );
+        self.write(TIMER_INITIAL_COUNT, count);
+
+        for io_apic in &self.io_apics {
+            for pin in 0..io_apic.pins {
+                io_apic.set_entry(pin, ENTRY_MASKED);
+            }
+        }
+        self.route(InterruptIndex::Keyboard);
+        self.route(InterruptIndex::Serial);
+    }
+
+    /// the count of the timer, divided by 16, during `PIT_COUNTS` cycles of the PIT
+    unsafe fn calibrate_timer(&self) -> u32 {
+        let mut control = Port::<u8>::new(0x61);
+        let mut command = Port::<u8>::new(0x43);
+        let mut channel_2 = Port::<u8>::new(0x42);
+
+        // the gate of the channel 2 low while it is set up, and the speaker off
+        let gate = control.read() & !0b11;
+        control.write(gate);
+        // channel 2, low then high byte, mode 0: the output goes high at the end
+        command.write(0b1011_0000);
+        channel_2.write(PIT_COUNTS as u8);
+        channel_2.write((PIT_COUNTS >> 8) as u8);
+
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(TIMER_DIVIDE, DIVIDE_BY_16);
+        self.write(TIMER_INITIAL_COUNT, u32::MAX);
+        control.write(gate | 1);
+        // the output of the channel 2 in bit 5
+        while control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let elapsed = u32::MAX - self.read(TIMER_CURRENT_COUNT);
+        self.write(TIMER_INITIAL_COUNT, 0);
+        control.write(gate);
+        elapsed
+    }
+
+    /// the I/O APIC pin of the ISA interrupt `irq` and the flags of its entry
+    fn pin_of(&self, irq: u8) -> Option<(&IoApic, u32, u64)> {
+        let (gsi, flags) = match self.overrides.iter().find(|entry| entry.irq == irq) {
+            Some(entry) => (entry.gsi, entry.flags),
+            None => (irq as u32, 0),
+        };
+        let mut entry_flags = 0;
+        if flags & POLARITY_ACTIVE_LOW == POLARITY_ACTIVE_LOW {
+            entry_flags |= ACTIVE_LOW;
+        }
+        if flags & TRIGGER_LEVEL == TRIGGER_LEVEL {
+            entry_flags |= LEVEL_TRIGGERED;
+        }
+        let io_apic = self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))?;
+        Some((io_apic, gsi - io_apic.gsi_base, entry_flags))
+    }
+
+    /// sends the interrupt of the device of `index` to this cpu
+    unsafe fn route(&self, index: InterruptIndex) {
+        let irq = index.irq().expect("apic: not a device interrupt");
+        match self.pin_of(irq) {
+            Some((io_apic, pin, flags)) => {
+                let destination = (self.id() as u64) << 56;
+                io_apic.set_entry(pin, destination | flags | index.as_u8() as u64);
+            }
+            None => {
+                crate::sprintln!("apic: no I/O APIC pin for irq {}", irq);
+            }
+        }
+    }
+}
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        "apic"
+    }
+
+    fn end_of_interrupt(&self, _index: InterruptIndex) {
+        unsafe { self.write(EOI, 0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::interrupts;
+
+    #[test_case]
+    fn test_devices_are_routed() {
+        let apic = interrupts::installed_apic().unwrap();
+        for index in [InterruptIndex::Keyboard, InterruptIndex::Serial] {
+            let (io_apic, pin, _) = apic.pin_of(index.irq().unwrap()).unwrap();
+            let entry = io_apic.entry(pin);
+            assert_eq!(entry & ENTRY_MASKED, 0);
+            assert_eq!(entry as u8, index.as_u8());
+            assert_eq!((entry >> 56) as u8, apic.id());
+        }
+        // the PIT, replaced by the timer
+        let (io_apic, pin, _) = apic.pin_of(0).unwrap();
+        assert_ne!(io_apic.entry(pin) & ENTRY_MASKED, 0);
+    }
+
+    #[test_case]
+    fn test_timer_is_periodic() {
+        let apic = interrupts::installed_apic().unwrap();
+        let lvt = apic.read(LVT_TIMER);
+        assert_eq!(lvt & LVT_MASKED, 0);
+        assert_ne!(lvt & TIMER_PERIODIC, 0);
+        assert_eq!(lvt as u8, InterruptIndex::Timer.as_u8());
+        assert_ne!(apic.read(TIMER_INITIAL_COUNT), 0);
+    }
+}
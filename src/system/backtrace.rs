@@ -0,0 +1,164 @@
+//! Backtraces, for the panics and the fatal exceptions.
+//!
+//! The kernel is built with frame pointers: each frame starts with the `rbp` of its
+//! caller, followed by the return address. The chain is walked as long as it points to
+//! mapped memory, and the return addresses are resolved against the symbol table
+//! `tools/ksyms.py` writes into `SYMBOLS` once the kernel is linked, see
+//! `tools/runner.sh`. Without it, the addresses are printed alone.
+//!
+//! The table: `MAGIC`, the number of functions, then for each, by address, its
+//! address (u64), size, name offset and name length (u32), then the names.
+
+use core::mem::size_of;
+use core::ptr::addr_of;
+use x86_64::VirtAddr;
+
+use crate::system::paging;
+use crate::{kprintln, sprintln};
+
+/// room for the symbol table, `tools/ksyms.py` leaves it empty if the table doesn't fit
+const SYMBOLS_SIZE: usize = 512 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 20;
+/// a longer chain is most likely a loop
+const MAX_FRAMES: usize = 32;
+
+// filled after the link, read through `symbols` only: the compiler would fold
+// the reads of the zeros it was initialized with
+#[used]
+#[link_section = ".ksyms"]
+static mut SYMBOLS: [u8; SYMBOLS_SIZE] = [0; SYMBOLS_SIZE];
+
+fn symbols() -> &'static [u8] {
+    unsafe { &*core::hint::black_box(addr_of!(SYMBOLS)) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// the function containing `addr` and the offset of `addr` in it,
+/// None if it is not in the table, or there is no table
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let table = symbols();
+    if table[..4] != MAGIC[..] {
+        return None;
+    }
+    let count = read_u32(table, 4) as usize;
+    let entry = |index: usize| HEADER_SIZE + index * ENTRY_SIZE;
+    // the first function past `addr`, the one before may contain it
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if read_u64(table, entry(middle)) <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let offset = entry(low.checked_sub(1)?);
+    let start = read_u64(table, offset);
+    let size = read_u32(table, offset + 8) as u64;
+    if addr - start >= size {
+        return None;
+    }
+    let name_offset = read_u32(table, offset + 12) as usize;
+    let name_len = read_u32(table, offset + 16) as usize;
+    let name = table.get(name_offset..name_offset + name_len)?;
+    Some((core::str::from_utf8(name).ok()?, addr - start))
+}
+
+/// the return addresses of the frames up the chain from `rbp`
+pub fn frames(rbp: u64) -> impl Iterator<Item = u64> {
+    let mut rbp = rbp;
+    core::iter::from_fn(move || {
+        let readable = |addr: u64| {
+            addr != 0 && addr & 7 == 0 && paging::is_mapped(VirtAddr::new_truncate(addr))
+        };
+        if !readable(rbp) || !readable(rbp.wrapping_add(size_of::<u64>() as u64)) {
+            return None;
+        }
+        let frame = rbp as *const u64;
+        let (caller_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            return None;
+        }
+        rbp = caller_rbp;
+        Some(return_address)
+    })
+    .take(MAX_FRAMES)
+}
+
+/// the `rbp` of the caller
+#[inline(always)]
+pub fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// prints on the screen and the serial port the backtrace of the code that was at
+/// `instruction_pointer`, if any, with the frame chain from `rbp`
+pub fn print(instruction_pointer: Option<u64>, rbp: u64) {
+    kprintln!("backtrace:");
+    sprintln!("backtrace:");
+    // the return addresses point past the call, and maybe past the function
+    let addresses = instruction_pointer
+        .into_iter()
+        .chain(frames(rbp).map(|addr| addr - 1));
+    for (index, addr) in addresses.enumerate() {
+        match symbolize(addr) {
+            Some((name, offset)) => {
+                kprintln!("  {:2}: {:#x} {}+{:#x}", index, addr, name, offset);
+                sprintln!("  {:2}: {:#x} {}+{:#x}", index, addr, name, offset);
+            }
+            None => {
+                kprintln!("  {:2}: {:#x}", index, addr);
+                sprintln!("  {:2}: {:#x}", index, addr);
+            }
+        }
+    }
+}
+
+/// prints the backtrace of the caller
+#[inline(always)]
+pub fn print_current() {
+    print(None, current_rbp());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn return_addresses() -> [u64; 2] {
+        let mut frames = frames(current_rbp());
+        [frames.next().unwrap(), frames.next().unwrap()]
+    }
+
+    #[inline(never)]
+    fn caller() -> [u64; 2] {
+        let addresses = return_addresses();
+        core::hint::black_box(addresses)
+    }
+
+    #[test_case]
+    fn test_frames_return_to_callers() {
+        let [in_caller, in_test] = caller();
+        let (name, _) = symbolize(in_caller - 1).expect("no symbol table");
+        assert!(name.ends_with("backtrace::tests::caller"), "{}", name);
+        // the test itself may be inlined in the test runner
+        assert!(symbolize(in_test - 1).is_some());
+    }
+
+    #[test_case]
+    fn test_unknown_address() {
+        assert_eq!(symbolize(0), None);
+        assert_eq!(frames(0).count(), 0);
+    }
+}
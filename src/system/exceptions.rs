@@ -0,0 +1,491 @@
+//! The handlers of the cpu exceptions, installed by `idt`.
+//!
+//! Each one reports the thread it hit, its registers, CR2 and CR3, and the decoded
+//! error code, then the fatal ones print the backtrace of the code they interrupted.
+//! A fault in ring 3, or in a kernel thread, ends the thread with `FAULT_EXIT_CODE`
+//! and the others keep running; in the kernel itself, the boot thread or the idle
+//! one, it panics. Debug, NMI and breakpoint are reported and resumed. The page
+//! faults the kernel can resolve are not reported at all, see
+//! `kernel::resolve_page_fault`.
+
+use core::fmt;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+use crate::kernel::{self, CpuRegs, StackFrame};
+use crate::kprintln;
+use crate::system::backtrace;
+
+/// RFLAGS.TF
+const TRAP_FLAG: u64 = 1 << 8;
+
+// the registers when the last exception was raised, see `snapshot_regs`
+static mut REGS: CpuRegs = CpuRegs::DEFAULT;
+
+/// copies the registers to `REGS`, must come first in the handler
+///
+/// as with `save_regs_to_current`, the prologue has only saved registers by then,
+/// except `rbp`: with the frame pointers on, it points to the interrupted one
+macro_rules! snapshot_regs {
+    () => {
+        core::arch::asm!(
+            "mov [rip + {regs}], rax",
+            "mov [rip + {regs} + 8], rbx",
+            "mov [rip + {regs} + 16], rcx",
+            "mov [rip + {regs} + 24], rdx",
+            "mov [rip + {regs} + 32], rsi",
+            "mov [rip + {regs} + 40], rdi",
+            "mov rax, [rbp]",
+            "mov [rip + {regs} + 48], rax",
+            "mov rax, [rip + {regs}]",
+            "mov [rip + {regs} + 56], r8",
+            "mov [rip + {regs} + 64], r9",
+            "mov [rip + {regs} + 72], r10",
+            "mov [rip + {regs} + 80], r11",
+            "mov [rip + {regs} + 88], r12",
+            "mov [rip + {regs} + 96], r13",
+            "mov [rip + {regs} + 104], r14",
+            "mov [rip + {regs} + 112], r15",
+            regs = sym REGS,
+        )
+    };
+}
+
+/// a handler that reports the fault and ends the thread, see `fault`
+macro_rules! fault_handler {
+    ($name:ident, $title:expr) => {
+        pub extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+            unsafe { snapshot_regs!() };
+            fault(&mut stack_frame, $title, ErrorCode::None);
+        }
+    };
+    ($name:ident, $title:expr, $decode:path) => {
+        pub extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64) {
+            unsafe { snapshot_regs!() };
+            fault(&mut stack_frame, $title, $decode(error_code));
+        }
+    };
+}
+
+enum ErrorCode {
+    None,
+    /// the segment selector involved, if any
+    Selector(u64),
+    Page(PageFaultErrorCode),
+    /// the kind of control flow transfer that failed
+    ControlProtection(u64),
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Selector(0) => write!(f, "0"),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{:#x}: {} index {}", code, table, (code >> 3) & 0x1fff)?;
+                if code & 1 != 0 {
+                    write!(f, ", external event")?;
+                }
+                Ok(())
+            }
+            ErrorCode::Page(code) => {
+                let write = code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+                let present = code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+                let access = match (write, present) {
+                    (false, false) => "read of an unmapped page",
+                    (true, false) => "write to an unmapped page",
+                    (false, true) => "read of a protected page",
+                    (true, true) => "write to a protected page",
+                };
+                write!(f, "{:#x}: {}", code.bits(), access)?;
+                if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                    write!(f, ", instruction fetch")?;
+                }
+                if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                    write!(f, ", reserved bit set in a table")?;
+                }
+                let ring = if code.contains(PageFaultErrorCode::USER_MODE) {
+                    3
+                } else {
+                    0
+                };
+                write!(f, ", from ring {}", ring)
+            }
+            ErrorCode::ControlProtection(code) => {
+                let kind = match code & 0x7fff {
+                    1 => "near return",
+                    2 => "far return or iret",
+                    3 => "missing end branch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x}: {}", code, kind)
+            }
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// prints what the exception hit, see the module documentation
+fn report(title: &str, stack_frame: &InterruptStackFrame, error_code: ErrorCode) {
+    let regs = unsafe { REGS };
+    let (level_4, _) = Cr3::read();
+    kprintln!(
+        "EXCEPTION: {} in thread {}\n  error code: {}",
+        title,
+        kernel::thread_id(),
+        error_code
+    );
+    kprintln!(
+        "  rip {:#018x} cs {:#x} rflags {:#x} rsp {:#018x} ss {:#x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags,
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment
+    );
+    kprintln!(
+        "  rax {:#018x} rbx {:#018x} rcx {:#018x} rdx {:#018x}",
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx
+    );
+    kprintln!(
+        "  rsi {:#018x} rdi {:#018x} rbp {:#018x} r8  {:#018x}",
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        regs.r8
+    );
+    kprintln!(
+        "  r9  {:#018x} r10 {:#018x} r11 {:#018x} r12 {:#018x}",
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12
+    );
+    kprintln!(
+        "  r13 {:#018x} r14 {:#018x} r15 {:#018x}",
+        regs.r13,
+        regs.r14,
+        regs.r15
+    );
+    kprintln!(
+        "  cr2 {:#018x} cr3 {:#018x}",
+        Cr2::read().as_u64(),
+        level_4.start_address().as_u64()
+    );
+}
+
+/// the backtrace of the code the exception interrupted
+fn print_backtrace(stack_frame: &InterruptStackFrame) {
+    let rbp = unsafe { REGS.rbp };
+    backtrace::print(Some(stack_frame.instruction_pointer.as_u64()), rbp);
+}
+
+/// reports the fault, then ends the current thread with `FAULT_EXIT_CODE`,
+/// or panics if it was the kernel's own
+fn fault(stack_frame: &mut InterruptStackFrame, title: &str, error_code: ErrorCode) -> ! {
+    report(title, stack_frame, error_code);
+    print_backtrace(stack_frame);
+    let user = stack_frame.code_segment & 3 == 3;
+    if user || unsafe { kernel::can_kill_current_thread() } {
+        let stack_frame_ptr = stack_frame as *mut _ as *mut StackFrame;
+        unsafe { kernel::kill_current_thread(kernel::FAULT_EXIT_CODE, stack_frame_ptr) };
+    }
+    // returning would only fault again
+    panic!(
+        "EXCEPTION: {} in the kernel at {:?}",
+        title, stack_frame.instruction_pointer
+    );
+}
+
+fault_handler!(divide_error, "DIVIDE ERROR");
+fault_handler!(overflow, "OVERFLOW");
+fault_handler!(bound_range_exceeded, "BOUND RANGE EXCEEDED");
+fault_handler!(invalid_opcode, "INVALID OPCODE");
+fault_handler!(invalid_tss, "INVALID TSS", ErrorCode::Selector);
+fault_handler!(
+    segment_not_present,
+    "SEGMENT NOT PRESENT",
+    ErrorCode::Selector
+);
+fault_handler!(
+    stack_segment_fault,
+    "STACK SEGMENT FAULT",
+    ErrorCode::Selector
+);
+fault_handler!(
+    general_protection_fault,
+    "GENERAL PROTECTION FAULT",
+    ErrorCode::Selector
+);
+fault_handler!(x87_floating_point, "X87 FLOATING POINT");
+fault_handler!(alignment_check, "ALIGNMENT CHECK", ErrorCode::Raw);
+fault_handler!(simd_floating_point, "SIMD FLOATING POINT");
+fault_handler!(virtualization, "VIRTUALIZATION");
+fault_handler!(
+    cp_protection_exception,
+    "CONTROL PROTECTION",
+    ErrorCode::ControlProtection
+);
+fault_handler!(hv_injection_exception, "HYPERVISOR INJECTION");
+fault_handler!(
+    vmm_communication_exception,
+    "VMM COMMUNICATION",
+    ErrorCode::Raw
+);
+fault_handler!(security_exception, "SECURITY", ErrorCode::Raw);
+
+/// single step or `int1`, the thread goes on, without the trap flag
+pub extern "x86-interrupt" fn debug(mut stack_frame: InterruptStackFrame) {
+    unsafe { snapshot_regs!() };
+    report("DEBUG", &stack_frame, ErrorCode::None);
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.cpu_flags &= !TRAP_FLAG)
+    };
+}
+
+pub extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: InterruptStackFrame) {
+    unsafe { snapshot_regs!() };
+    report("NON MASKABLE INTERRUPT", &stack_frame, ErrorCode::None);
+}
+
+pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
+    unsafe { snapshot_regs!() };
+    report("BREAKPOINT", &stack_frame, ErrorCode::None);
+}
+
+/// on its own stack, see `gdt::PAGE_FAULT_IST_INDEX`
+pub extern "x86-interrupt" fn page_fault(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    unsafe { snapshot_regs!() };
+    let addr = Cr2::read();
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    if unsafe { kernel::resolve_page_fault(addr, write, present) } {
+        return;
+    }
+    let stack_frame_ptr = &mut stack_frame as *mut _ as *mut StackFrame;
+    unsafe { kernel::check_stack_overflow(addr, stack_frame_ptr) };
+
+    fault(&mut stack_frame, "PAGE FAULT", ErrorCode::Page(error_code));
+}
+
+pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    unsafe { snapshot_regs!() };
+    report("DOUBLE FAULT", &stack_frame, ErrorCode::Raw(error_code));
+    print_backtrace(&stack_frame);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT at {:?}",
+        stack_frame.instruction_pointer
+    );
+}
+
+/// the state of the cpu can't be trusted anymore
+pub extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
+    unsafe { snapshot_regs!() };
+    report("MACHINE CHECK", &stack_frame, ErrorCode::None);
+    print_backtrace(&stack_frame);
+    panic!(
+        "EXCEPTION: MACHINE CHECK at {:?}",
+        stack_frame.instruction_pointer
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{join, launch, spawn_process, user, wait_process, FAULT_EXIT_CODE};
+    use core::arch::asm;
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+
+    /// runs `fault` in a kernel thread, which the exception must end
+    fn assert_kills_thread(fault: fn()) {
+        let id = launch(fault).unwrap();
+        assert_eq!(join(id), FAULT_EXIT_CODE);
+    }
+
+    #[test_case]
+    fn test_divide_error() {
+        assert_kills_thread(|| unsafe {
+            asm!("div rcx", in("rcx") 0u64, inout("rax") 1u64 => _, inout("rdx") 0u64 => _)
+        });
+    }
+
+    #[test_case]
+    fn test_invalid_opcode() {
+        assert_kills_thread(|| unsafe { asm!("ud2") });
+    }
+
+    #[test_case]
+    fn test_general_protection_fault() {
+        // a non-canonical address
+        assert_kills_thread(|| unsafe {
+            asm!("mov rax, [rcx]", in("rcx") 1u64 << 63, out("rax") _)
+        });
+    }
+
+    #[test_case]
+    fn test_stack_segment_fault() {
+        // the same, relative to the stack pointer
+        assert_kills_thread(|| unsafe {
+            asm!("mov rax, [rsp + rcx]", in("rcx") 1u64 << 63, out("rax") _)
+        });
+    }
+
+    // the thread never gets past the fault, the FPU and SSE registers it clobbers
+    // are not declared
+
+    #[test_case]
+    fn test_x87_floating_point() {
+        // 1 / 0, with the zero divide exception unmasked
+        assert_kills_thread(|| unsafe {
+            asm!(
+                "sub rsp, 8",
+                "fninit",
+                "mov word ptr [rsp], 0x037b",
+                "fldcw word ptr [rsp]",
+                "mov dword ptr [rsp], 0",
+                "fld1",
+                "fidiv dword ptr [rsp]",
+                "fwait",
+                "add rsp, 8",
+            )
+        });
+    }
+
+    #[test_case]
+    fn test_simd_floating_point() {
+        // 1.0 / 0.0, with the zero divide exception unmasked
+        assert_kills_thread(|| unsafe {
+            asm!(
+                "sub rsp, 8",
+                "mov dword ptr [rsp], 0x1d80",
+                "ldmxcsr dword ptr [rsp]",
+                "mov dword ptr [rsp], 0x3f800000",
+                "movss xmm1, dword ptr [rsp]",
+                "xorps xmm0, xmm0",
+                "divss xmm1, xmm0",
+                "add rsp, 8",
+            )
+        });
+    }
+
+    /// a misaligned read with RFLAGS.AC set, only checked in ring 3
+    #[link_section = "user_text"]
+    extern "C" fn misaligned_read() {
+        unsafe {
+            asm!(
+                "pushfq",
+                "or qword ptr [rsp], 0x40000",
+                "popfq",
+                "mov rax, [rsp - 15]",
+                out("rax") _,
+            )
+        };
+        user::exit(0);
+    }
+
+    #[test_case]
+    fn test_alignment_check() {
+        let pid = spawn_process("misaligned", misaligned_read).unwrap();
+        assert_eq!(wait_process(pid), FAULT_EXIT_CODE);
+    }
+
+    /// the exceptions that can't be caused from here and push no error code are
+    /// raised with `int`: only the end of the thread is checked
+    #[test_case]
+    fn test_raised_faults() {
+        let faults: [fn(); 4] = [
+            || unsafe { asm!("int 4") },  // overflow
+            || unsafe { asm!("int 5") },  // bound range exceeded
+            || unsafe { asm!("int 20") }, // virtualization
+            || unsafe { asm!("int 28") }, // hypervisor injection
+        ];
+        for fault in faults {
+            assert_kills_thread(fault);
+        }
+    }
+
+    /// a closure that jumps to `$handler` with the frame the cpu pushes for an
+    /// exception with an error code, which `int` would leave out
+    macro_rules! with_error_code {
+        ($handler:path, $code:expr) => {
+            || unsafe {
+                let cs = CS::get_reg().0 as u64;
+                let ss = SS::get_reg().0 as u64;
+                asm!(
+                    "mov rax, rsp",
+                    "and rsp, -16",
+                    "push {ss}",
+                    "push rax",
+                    "pushfq",
+                    "push {cs}",
+                    "lea rax, [rip + 2f]",
+                    "push rax",
+                    "push {code}",
+                    // an interrupt gate
+                    "cli",
+                    "jmp {handler}",
+                    "2:",
+                    ss = in(reg) ss,
+                    cs = in(reg) cs,
+                    code = in(reg) $code as u64,
+                    handler = sym $handler,
+                    out("rax") _,
+                )
+            }
+        };
+    }
+
+    #[test_case]
+    fn test_faults_with_error_codes() {
+        let faults: [fn(); 5] = [
+            with_error_code!(invalid_tss, 0x28),
+            with_error_code!(segment_not_present, 0x30),
+            with_error_code!(cp_protection_exception, 3),
+            with_error_code!(vmm_communication_exception, 0x7b),
+            with_error_code!(security_exception, 0),
+        ];
+        for fault in faults {
+            assert_kills_thread(fault);
+        }
+    }
+
+    #[test_case]
+    fn test_debug_resumes() {
+        let flags: u64;
+        unsafe {
+            asm!(
+                // single step, the handler clears the flag after the first one
+                "pushfq",
+                "or qword ptr [rsp], 0x100",
+                "popfq",
+                "nop",
+                "nop",
+                "pushfq",
+                "pop {}",
+                out(reg) flags,
+            )
+        };
+        assert_eq!(flags & TRAP_FLAG, 0);
+    }
+
+    #[test_case]
+    fn test_non_maskable_interrupt_resumes() {
+        unsafe { asm!("int 2") };
+    }
+}
@@ -0,0 +1,198 @@
+//! The physical frame allocator, over the usable memory in the bootloader's memory map.
+//!
+//! A bitmap holds one bit per frame, from address 0 to the end of the last usable
+//! region, set when the frame is in use or not usable. It lives in the first usable
+//! region large enough, accessed through the mapping of the physical memory.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::paging;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = u64::BITS as usize;
+
+// locked with the interrupts disabled, syscalls allocate too
+static FRAMES: Mutex<Option<Bitmap>> = Mutex::new(None);
+
+struct Bitmap {
+    words: &'static mut [u64],
+    usable: usize,
+    free: usize,
+    // where the next search starts, below it the frames were in use last time
+    next: usize,
+}
+
+impl Bitmap {
+    fn is_used(&self, frame: usize) -> bool {
+        self.words[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if used {
+            self.words[frame / BITS] |= 1 << (frame % BITS);
+        } else {
+            self.words[frame / BITS] &= !(1 << (frame % BITS));
+        }
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        let start = self.next / BITS;
+        let word = (start..self.words.len())
+            .chain(0..start)
+            .find(|&word| self.words[word] != u64::MAX)?;
+        let frame = word * BITS + self.words[word].trailing_ones() as usize;
+        self.set_used(frame, true);
+        self.free -= 1;
+        self.next = frame + 1;
+        Some(frame)
+    }
+
+    fn deallocate(&mut self, frame: usize) {
+        assert!(
+            frame < self.words.len() * BITS && self.is_used(frame),
+            "deallocate: frame {:#x} is not allocated",
+            frame as u64 * FRAME_SIZE
+        );
+        self.set_used(frame, false);
+        self.free += 1;
+        self.next = self.next.min(frame);
+    }
+}
+
+/// takes over the usable regions of `memory_map`
+///
+/// safety: must be called once, after `paging::init`, nothing else may use those regions
+pub unsafe fn init(memory_map: &'static MemoryMap) {
+    let usable = || {
+        memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+    };
+    let frames = usable()
+        .map(|region| region.range.end_frame_number as usize)
+        .max()
+        .expect("no usable memory");
+    let words_len = frames.div_ceil(BITS);
+    let bitmap_frames = (words_len * 8).div_ceil(FRAME_SIZE as usize);
+    let bitmap_region = usable()
+        .find(|region| {
+            // frame 0 would be a null pointer through an identity mapping
+            region.range.start_frame_number > 0
+                && region.range.end_frame_number - region.range.start_frame_number
+                    >= bitmap_frames as u64
+        })
+        .expect("no usable region for the frame bitmap");
+    let words_addr = paging::phys_to_virt(PhysAddr::new(bitmap_region.range.start_addr()));
+    let words = core::slice::from_raw_parts_mut(words_addr.as_mut_ptr(), words_len);
+    words.fill(u64::MAX);
+
+    let mut bitmap = Bitmap {
+        words,
+        usable: 0,
+        free: 0,
+        next: 0,
+    };
+    for region in usable() {
+        for frame in region.range.start_frame_number..region.range.end_frame_number {
+            bitmap.set_used(frame as usize, false);
+            bitmap.usable += 1;
+        }
+    }
+    let bitmap_start = bitmap_region.range.start_frame_number as usize;
+    for frame in bitmap_start..bitmap_start + bitmap_frames {
+        bitmap.set_used(frame, true);
+    }
+    bitmap.free = bitmap.usable - bitmap_frames;
+    without_interrupts(|| *FRAMES.lock() = Some(bitmap));
+}
+
+fn with_bitmap<T>(f: impl FnOnce(&mut Bitmap) -> T) -> T {
+    without_interrupts(|| f(FRAMES.lock().as_mut().expect("frames::init not called")))
+}
+
+/// a free frame, not zeroed, None if out of memory
+pub fn allocate() -> Option<PhysFrame> {
+    let frame = with_bitmap(Bitmap::allocate)?;
+    Some(PhysFrame::containing_address(PhysAddr::new(
+        frame as u64 * FRAME_SIZE,
+    )))
+}
+
+/// gives back a frame from `allocate`
+///
+/// safety: nothing may use the frame anymore
+pub unsafe fn deallocate(frame: PhysFrame) {
+    let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+    with_bitmap(|bitmap| bitmap.deallocate(frame));
+}
+
+/// the number of usable frames not allocated
+pub fn free_frames() -> usize {
+    with_bitmap(|bitmap| bitmap.free)
+}
+
+/// the number of usable frames allocated, the bitmap's included
+pub fn used_frames() -> usize {
+    with_bitmap(|bitmap| bitmap.usable - bitmap.free)
+}
+
+/// `allocate` and `deallocate` for the `x86_64` mappers
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn test_counts_follow_allocations() {
+        let free = free_frames();
+        let used = used_frames();
+        let frame = allocate().unwrap();
+        assert_eq!(free_frames(), free - 1);
+        assert_eq!(used_frames(), used + 1);
+        unsafe { deallocate(frame) };
+        assert_eq!(free_frames(), free);
+        assert_eq!(used_frames(), used);
+    }
+
+    #[test_case]
+    fn test_frames_are_distinct_and_usable() {
+        let frames: Vec<PhysFrame> = (0..64).map(|_| allocate().unwrap()).collect();
+        for (index, frame) in frames.iter().enumerate() {
+            let addr: *mut u64 = paging::phys_to_virt(frame.start_address()).as_mut_ptr();
+            unsafe { addr.write(index as u64) };
+        }
+        for (index, frame) in frames.iter().enumerate() {
+            let addr: *const u64 = paging::phys_to_virt(frame.start_address()).as_ptr();
+            assert_eq!(unsafe { addr.read() }, index as u64);
+        }
+        for frame in frames {
+            unsafe { deallocate(frame) };
+        }
+    }
+
+    #[test_case]
+    fn test_freed_frame_is_reused() {
+        let frame = allocate().unwrap();
+        unsafe { deallocate(frame) };
+        assert_eq!(allocate(), Some(frame));
+        unsafe { deallocate(frame) };
+    }
+}
@@ -2,24 +2,17 @@ use lazy_static::lazy_static;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-use core::ptr::addr_of;
+use core::ptr::{addr_of, addr_of_mut};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// a stack overflow page faults with the stack pointer in the guard page,
+/// the handler needs a stack of its own
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const IST_STACK_SIZE: usize = 4096 * 5;
 
-            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// mutable: `privilege_stack_table[0]` follows the running thread, see `set_kernel_stack`
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 
@@ -28,30 +21,71 @@ use x86_64::structures::gdt::SegmentSelector;
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // in the order `syscall` and `sysretq` expect, see `kernel::user::init`
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
-                code_selector,
+                kernel_code,
+                kernel_data,
+                user_code,
+                user_data,
                 tss_selector,
             },
         )
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+/// the selectors of the user segments have their RPL set to 3
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// the stack the cpu switches to when an interrupt comes in ring 3
+pub fn set_kernel_stack(stack_end: VirtAddr) {
+    unsafe {
+        (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_end;
+    }
+}
+
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+            stack_start + IST_STACK_SIZE
+        };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+            stack_start + IST_STACK_SIZE
+        };
+    }
+
     GDT.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(GDT.1.kernel_code);
+        SS::set_reg(GDT.1.kernel_data);
+        DS::set_reg(GDT.1.kernel_data);
+        ES::set_reg(GDT.1.kernel_data);
         load_tss(GDT.1.tss_selector);
     }
 }
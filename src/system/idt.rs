@@ -1,60 +1,76 @@
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
 
-use crate::kprintln;
-use crate::system::gdt;
+use crate::system::interrupts::{self, InterruptIndex};
+use crate::system::{exceptions, gdt};
 
 use crate::kernel;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.divide_error.set_handler_fn(divide_error);
-        idt.page_fault.set_handler_fn(page_fault);
+        idt.divide_error.set_handler_fn(exceptions::divide_error);
+        idt.debug.set_handler_fn(exceptions::debug);
+        idt.non_maskable_interrupt
+            .set_handler_fn(exceptions::non_maskable_interrupt);
+        idt.breakpoint.set_handler_fn(exceptions::breakpoint);
+        idt.overflow.set_handler_fn(exceptions::overflow);
+        idt.bound_range_exceeded
+            .set_handler_fn(exceptions::bound_range_exceeded);
+        idt.invalid_opcode
+            .set_handler_fn(exceptions::invalid_opcode);
+        idt.device_not_available
+            .set_handler_fn(kernel::fpu::device_not_available_handler);
         unsafe {
+            idt.page_fault
+                .set_handler_fn(exceptions::page_fault)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_fn(exceptions::double_fault)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss);
+        idt.segment_not_present
+            .set_handler_fn(exceptions::segment_not_present);
+        idt.stack_segment_fault
+            .set_handler_fn(exceptions::stack_segment_fault);
+        idt.general_protection_fault
+            .set_handler_fn(exceptions::general_protection_fault);
+        idt.x87_floating_point
+            .set_handler_fn(exceptions::x87_floating_point);
+        idt.alignment_check
+            .set_handler_fn(exceptions::alignment_check);
+        idt.machine_check.set_handler_fn(exceptions::machine_check);
+        idt.simd_floating_point
+            .set_handler_fn(exceptions::simd_floating_point);
+        idt.virtualization
+            .set_handler_fn(exceptions::virtualization);
+        idt.cp_protection_exception
+            .set_handler_fn(exceptions::cp_protection_exception);
+        idt.hv_injection_exception
+            .set_handler_fn(exceptions::hv_injection_exception);
+        idt.vmm_communication_exception
+            .set_handler_fn(exceptions::vmm_communication_exception);
+        idt.security_exception
+            .set_handler_fn(exceptions::security_exception);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(kernel::timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::System.as_usize()].set_handler_fn(kernel::system_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        // the syscalls, ring 3 may use `int 0x80`
+        idt[InterruptIndex::System.as_usize()]
+            .set_handler_fn(kernel::system_interrupt_handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt
     };
 }
 
 pub fn init() {
     IDT.load();
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    kprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn divide_error(stack_frame: InterruptStackFrame) {
-    kprintln!("EXCEPTION: DIVIDE BY 0\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    kprintln!(
-        "EXCEPTION: page_fault\n{:#?}\n{:#?}\n",
-        stack_frame,
-        error_code
-    );
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!(
-        "EXCEPTION: DOUBLE FAULT\n{:#?};\n{}",
-        stack_frame, error_code
-    );
+    // ring 3 gets alignment checks when it sets RFLAGS.AC
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::ALIGNMENT_MASK)) };
 }
 
 #[test_case]
@@ -63,33 +79,6 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
-use pic8259::ChainedPics;
-use spin;
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-    System = 0x80,
-}
-
-impl InterruptIndex {
-    pub fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
-
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
@@ -100,12 +89,24 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     keyboard::handle_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    interrupts::end_of_interrupt(InterruptIndex::Keyboard);
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    use crate::drivers::serial::SERIAL1;
+
+    // nothing takes input from the serial port yet, the fifo is emptied so that
+    // the port raises the interrupt again
+    let mut serial = SERIAL1.lock();
+    while serial.try_receive().is_some() {}
+    drop(serial);
+
+    interrupts::end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// sent by the local APIC in place of an interrupt it dropped, there is nothing to acknowledge
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 // extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
 //     implemented in kernel.rs
 // }
@@ -0,0 +1,167 @@
+//! The interrupt controller, behind `InterruptController`.
+//!
+//! With ACPI, the local APIC and the I/O APICs of the MADT deliver the interrupts, and
+//! the 8259 PICs are masked, see `apic`. Otherwise the PICs do. The handlers only see
+//! the vectors of `InterruptIndex` and `end_of_interrupt`, whichever controller it is.
+
+use alloc::boxed::Box;
+use core::arch::x86_64::__cpuid;
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::sprintln;
+use crate::system::{acpi, apic::Apic};
+
+/// the vectors of the ISA interrupts, for both controllers
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+    System = 0x80,
+    /// sent by the local APIC for an interrupt it dropped, without end of interrupt
+    Spurious = 0xff,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+
+    /// the ISA interrupt line of the device, None for the software interrupts
+    pub fn irq(self) -> Option<u8> {
+        match self {
+            Self::Timer | Self::Keyboard | Self::Serial => Some(self.as_u8() - PIC_1_OFFSET),
+            Self::System | Self::Spurious => None,
+        }
+    }
+}
+
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+
+    /// acknowledges the interrupt `index` being handled, the controller holds back
+    /// the ones of lower priority until then
+    fn end_of_interrupt(&self, index: InterruptIndex);
+}
+
+// set once by `init`
+static mut CONTROLLER: Option<&'static dyn InterruptController> = None;
+// the same, when it is the APICs
+static mut APIC: Option<&'static Apic> = None;
+
+/// the two 8259 PICs, when there are no APICs
+pub struct Pic(Mutex<ChainedPics>);
+
+impl Pic {
+    /// remaps the PICs past the exceptions, with only the devices of
+    /// `InterruptIndex` unmasked
+    ///
+    /// safety: must be called once, with the interrupts disabled
+    pub unsafe fn new() -> Self {
+        let mut pics = ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET);
+        pics.initialize();
+        // the line 2 of the first one is the second one
+        let mask = [
+            InterruptIndex::Timer,
+            InterruptIndex::Keyboard,
+            InterruptIndex::Serial,
+        ]
+        .into_iter()
+        .filter_map(InterruptIndex::irq)
+        .fold(!(1u8 << 2), |mask, irq| mask & !(1 << irq));
+        Port::<u8>::new(0x21).write(mask);
+        Port::<u8>::new(0xa1).write(0xff);
+        Self(Mutex::new(pics))
+    }
+
+    /// masks all the lines of the PICs, still remapped past the exceptions in case
+    /// they send a spurious interrupt
+    ///
+    /// safety: must be called once, with the interrupts disabled
+    pub unsafe fn disable() {
+        ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET).initialize();
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 pic"
+    }
+
+    fn end_of_interrupt(&self, index: InterruptIndex) {
+        unsafe { self.0.lock().notify_end_of_interrupt(index.as_u8()) }
+    }
+}
+
+/// the APICs of the MADT if any, with the PICs disabled
+///
+/// safety: must be called once, with the interrupts disabled
+unsafe fn apic() -> Option<Apic> {
+    // CPUID.1:EDX.APIC
+    let madt = acpi::madt().filter(|_| __cpuid(1).edx & (1 << 9) != 0)?;
+    let apic = Apic::new(&madt)?;
+    if madt.has_pics {
+        Pic::disable();
+    }
+    apic.init();
+    Some(apic)
+}
+
+/// sets up the APICs, or the PICs without them, and starts the timer
+///
+/// safety: must be called once, after `regions::init`, with the interrupts disabled
+pub unsafe fn init() {
+    let controller: &'static dyn InterruptController = match apic() {
+        Some(apic) => {
+            let apic = Box::leak(Box::new(apic));
+            APIC = Some(apic);
+            apic
+        }
+        None => Box::leak(Box::new(Pic::new())),
+    };
+    CONTROLLER = Some(controller);
+    sprintln!("interrupts: {}", controller.name());
+}
+
+/// the controller `init` set up
+pub fn controller() -> &'static dyn InterruptController {
+    unsafe { CONTROLLER }.expect("interrupts: no controller, see init")
+}
+
+/// the APICs `init` set up, None if it fell back to the PICs
+#[cfg(test)]
+pub fn installed_apic() -> Option<&'static Apic> {
+    unsafe { APIC }
+}
+
+/// to be called by the handler of `index` once done with the device
+pub fn end_of_interrupt(index: InterruptIndex) {
+    controller().end_of_interrupt(index)
+}
+
+#[test_case]
+fn test_apic_replaces_the_pics() {
+    assert_eq!(controller().name(), "apic");
+    // all masked
+    assert_eq!(unsafe { Port::<u8>::new(0x21).read() }, 0xff);
+}
+
+#[test_case]
+fn test_timer_ticks() {
+    let start = crate::kernel::ticks();
+    while crate::kernel::ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
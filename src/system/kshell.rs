@@ -1,9 +1,14 @@
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use pc_keyboard::KeyCode;
 use spin::Mutex;
 
 use crate::drivers::keyboard::{set_keymap, Keymap};
 use crate::drivers::tty::GLOBAL_TTY;
+use crate::kernel::scheduler::{FixedPriority, Mlfq, RoundRobin, Scheduler};
+use crate::kernel::set_scheduler;
+use crate::kernel::syscall::set_tracing;
+use crate::system::{frames, memory, slab};
 use crate::{kprint, kprintln};
 
 pub struct KShell {
@@ -154,8 +159,25 @@ impl KShell {
     /// (name, function, help string)
     /// Each function takes the current KShell
     /// and the position of the first character after the command name
-    const BUILTINS: [(&'static str, fn(&KShell, usize), &'static str); 3] = [
+    const BUILTINS: [(&'static str, fn(&KShell, usize), &'static str); 8] = [
         ("keymap", Self::cmd_keymap, "change the keymap"),
+        ("mem", Self::cmd_mem, "print the memory in use"),
+        ("slabs", Self::cmd_slabs, "print the object caches"),
+        (
+            "allocs",
+            Self::cmd_allocs,
+            "dump the live allocations on the serial port (heap-debug)",
+        ),
+        (
+            "trace",
+            Self::cmd_trace,
+            "log the syscalls on the serial port (on/off)",
+        ),
+        (
+            "sched",
+            Self::cmd_sched,
+            "change the scheduling policy (rr/fixed/mlfq)",
+        ),
         ("help", Self::cmd_help, "print help for the shell"),
         ("quit", Self::cmd_quit, "quit"),
     ];
@@ -213,6 +235,84 @@ impl KShell {
         print_available();
     }
 
+    fn cmd_trace(&self, cmd_end: usize) {
+        let arg_start = match self.next_non_white(cmd_end) {
+            Some(i) => i,
+            None => {
+                kprintln!("Usage: trace on|off");
+                return;
+            }
+        };
+        let arg_end = self.next_white(arg_start);
+        if self.streq(arg_start, arg_end, "on") {
+            set_tracing(true);
+        } else if self.streq(arg_start, arg_end, "off") {
+            set_tracing(false);
+        } else {
+            kprintln!("Usage: trace on|off");
+        }
+    }
+
+    fn cmd_sched(&self, cmd_end: usize) {
+        let arg_start = match self.next_non_white(cmd_end) {
+            Some(i) => i,
+            None => {
+                kprintln!("Usage: sched rr|fixed|mlfq");
+                return;
+            }
+        };
+        let arg_end = self.next_white(arg_start);
+        let scheduler: Box<dyn Scheduler> = if self.streq(arg_start, arg_end, "rr") {
+            Box::new(RoundRobin::new())
+        } else if self.streq(arg_start, arg_end, "fixed") {
+            Box::new(FixedPriority::new())
+        } else if self.streq(arg_start, arg_end, "mlfq") {
+            Box::new(Mlfq::new())
+        } else {
+            kprintln!("Usage: sched rr|fixed|mlfq");
+            return;
+        };
+        kprintln!("scheduler: {}", scheduler.name());
+        set_scheduler(scheduler);
+    }
+
+    fn cmd_mem(&self, _: usize) {
+        let used = frames::used_frames();
+        let free = frames::free_frames();
+        kprintln!(
+            "{} KiB used, {} KiB free ({} frames of 4 KiB)",
+            used * 4,
+            free * 4,
+            used + free
+        );
+        let heap = memory::stats();
+        kprintln!(
+            "heap: {} KiB used of {} KiB, at most {} KiB",
+            heap.used / 1024,
+            heap.size / 1024,
+            heap.max_size / 1024
+        );
+    }
+
+    fn cmd_slabs(&self, _: usize) {
+        for cache in slab::all_stats() {
+            kprintln!(
+                "{}: {} of {} objects of {} bytes in use, {} slabs, {} allocs, {} frees",
+                cache.name,
+                cache.in_use,
+                cache.capacity,
+                cache.object_size,
+                cache.slabs,
+                cache.allocs,
+                cache.frees
+            );
+        }
+    }
+
+    fn cmd_allocs(&self, _: usize) {
+        memory::dump_allocations();
+    }
+
     fn cmd_help(&self, _: usize) {
         kprintln!("Primoria KShell");
         kprintln!("Commands:");
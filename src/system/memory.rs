@@ -1,49 +1,467 @@
+//! The kernel heap.
+//!
+//! It lives in a level 4 entry of its own, mapped from the start up to its current
+//! size, and grows by mapping fresh frames at its end when no free block is large
+//! enough, up to `max_size`. Past that, allocations fail and the kernel panics with
+//! a report of the heap usage.
+//!
+//! The free blocks form a list sorted by address, allocations take the first one
+//! large enough, and a freed block is merged with the free blocks right before and
+//! after it. A free block keeps its size and the next one in its first bytes, so
+//! every block is a multiple of `BLOCK_SIZE`, aligned at least as much.
+//!
+//! With the `heap-debug` feature, every allocation gets a record of its size and
+//! callers, and red zones before and after it. Freeing checks the red zones and
+//! catches double frees, then poisons the memory. `dump_allocations` lists the live
+//! ones on the serial port. The callers come from the frame pointers, which the
+//! targets keep.
+
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{frames, paging};
 
 const KB: usize = 1024;
-const MEM_SIZE: usize = 8 * 1024 * KB;
-static mut MEMORY: [u8; MEM_SIZE] = [0; MEM_SIZE];
+const MB: usize = 1024 * KB;
+const PAGE_SIZE: usize = 4096;
+/// mapped by `init`
+const INITIAL_SIZE: usize = MB;
+/// the least the heap grows by at once
+const GROWTH: usize = MB;
+pub const DEFAULT_MAX_SIZE: usize = 256 * MB;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const _: () = assert!(BLOCK_SIZE == 16 && align_of::<FreeBlock>() <= BLOCK_SIZE);
+
+struct Heap {
+    // the free block with the lowest address
+    first: *mut FreeBlock,
+    // 0 until `init`
+    start: usize,
+    size: usize,
+    max_size: usize,
+    // in blocks, rounded as `block_layout` does
+    used: usize,
+    // the most recent live allocation
+    #[cfg(feature = "heap-debug")]
+    live: *mut debug::Record,
+}
+
+// only reached through `Allocator::heap`
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// gives the block at `addr` to the free list, merged with its neighbours
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.first;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+        assert!(
+            next.is_null() || addr + size <= next as usize,
+            "heap: freeing {:#x} twice",
+            addr
+        );
+        assert!(
+            prev.is_null() || prev as usize + (*prev).size <= addr,
+            "heap: freeing {:#x} twice",
+            addr
+        );
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.first = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// first fit, null if no free block is large enough
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut block = self.first;
+        while !block.is_null() {
+            let start = block as usize;
+            let end = start + (*block).size;
+            // what is left before is a multiple of `BLOCK_SIZE`, free again below
+            let addr = align_up(start, align);
+            if addr + size <= end {
+                let next = (*block).next;
+                if prev.is_null() {
+                    self.first = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if addr > start {
+                    self.free(start, addr - start);
+                }
+                if addr + size < end {
+                    self.free(addr + size, end - (addr + size));
+                }
+                return addr as *mut u8;
+            }
+            prev = block;
+            block = (*block).next;
+        }
+        null_mut()
+    }
+
+    /// maps at least `bytes` more at the end of the heap, false if that would go past
+    /// `max_size` or the frames are out
+    unsafe fn grow(&mut self, bytes: usize) -> bool {
+        let wanted = align_up(bytes, PAGE_SIZE);
+        let bytes = align_up(bytes.max(GROWTH), PAGE_SIZE).min(self.max_size - self.size);
+        if self.start == 0 || bytes < wanted {
+            return false;
+        }
+        let end = self.start + self.size;
+        let mut mapped = 0;
+        while mapped < bytes {
+            let page = Page::containing_address(VirtAddr::new((end + mapped) as u64));
+            let frame = match frames::allocate() {
+                Some(frame) => frame,
+                None => break,
+            };
+            if paging::map(page, frame, PageTableFlags::WRITABLE).is_err() {
+                frames::deallocate(frame);
+                break;
+            }
+            mapped += PAGE_SIZE;
+        }
+        // what was mapped is kept, even if it falls short
+        if mapped > 0 {
+            self.size += mapped;
+            self.free(end, mapped);
+        }
+        mapped == bytes
+    }
+}
 
 pub struct Allocator {
-    index: AtomicUsize,
+    // locked with the interrupts disabled, the scheduler allocates too
+    heap: Mutex<Heap>,
 }
 
 impl Allocator {
     const fn new() -> Allocator {
         Allocator {
-            index: AtomicUsize::new(0),
+            heap: Mutex::new(Heap {
+                first: null_mut(),
+                start: 0,
+                size: 0,
+                max_size: DEFAULT_MAX_SIZE,
+                used: 0,
+                #[cfg(feature = "heap-debug")]
+                live: null_mut(),
+            }),
         }
     }
+
+    fn with_heap<T>(&self, f: impl FnOnce(&mut Heap) -> T) -> T {
+        without_interrupts(|| f(&mut self.heap.lock()))
+    }
+
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        self.with_heap(|heap| {
+            let mut block = heap.alloc(size, align);
+            // enough for the block wherever the alignment puts it
+            if block.is_null() && heap.grow(size + align) {
+                block = heap.alloc(size, align);
+            }
+            if !block.is_null() {
+                heap.used += size;
+            }
+            block
+        })
+    }
+
+    unsafe fn free_block(&self, block: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.with_heap(|heap| {
+            heap.free(block as usize, size);
+            heap.used -= size;
+        })
+    }
+}
+
+/// sets up the heap, nothing can be allocated before
+///
+/// safety: must be called once, after `frames::init`, before any process is created
+pub unsafe fn init() {
+    let start = paging::claim_level_4_entry(false).as_u64() as usize;
+    ALLOCATOR.with_heap(|heap| {
+        heap.start = start;
+        assert!(heap.grow(INITIAL_SIZE), "no memory for the heap");
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// mapped so far
+    pub size: usize,
+    /// allocated, rounded up to whole blocks
+    pub used: usize,
+    pub max_size: usize,
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.with_heap(|heap| HeapStats {
+        size: heap.size,
+        used: heap.used,
+        max_size: heap.max_size,
+    })
+}
+
+/// the size the heap may grow to, it does not shrink below its current size
+pub fn set_max_size(bytes: usize) {
+    ALLOCATOR.with_heap(|heap| heap.max_size = bytes.max(heap.size));
+}
+
+/// the size and alignment of the block for `layout`
+fn block_layout(layout: Layout) -> (usize, usize) {
+    (
+        align_up(layout.size().max(BLOCK_SIZE), BLOCK_SIZE),
+        layout.align().max(BLOCK_SIZE),
+    )
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(not(feature = "heap-debug"))]
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_block(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.free_block(ptr, layout)
+    }
 }
 
+#[cfg(feature = "heap-debug")]
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
+        let callers = debug::callers();
+        let block = self.alloc_block(debug::block_layout(layout));
+        if block.is_null() {
+            return block;
+        }
+        self.with_heap(|heap| debug::track(&mut heap.live, block, layout, callers))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // before locking, the panic handler may allocate
+        debug::check(ptr, layout);
+        let block = self.with_heap(|heap| debug::untrack(&mut heap.live, ptr, layout));
+        self.free_block(block, debug::block_layout(layout));
+    }
+}
+
+/// prints the live allocations on the serial port, with the `heap-debug` feature
+pub fn dump_allocations() {
+    #[cfg(feature = "heap-debug")]
+    ALLOCATOR.with_heap(|heap| unsafe { debug::dump(heap.live) });
+    #[cfg(not(feature = "heap-debug"))]
+    crate::sprintln!("heap: built without the heap-debug feature");
+}
+
+#[cfg(feature = "heap-debug")]
+mod debug {
+    use super::*;
+    use crate::sprintln;
+
+    const RED_ZONE: usize = 16;
+    const RED_ZONE_BYTE: u8 = 0xfd;
+    // new memory, to spot what is read before being written
+    const ALLOCATED_BYTE: u8 = 0xcd;
+    const FREED_BYTE: u8 = 0xdd;
+    const LIVE: u64 = 0x6865_6170_6c69_7665;
+    const FREED: u64 = 0x6865_6170_6672_6565;
+    const CALLERS: usize = 6;
 
-        let base_address = MEMORY.as_ptr() as usize;
-        let current_address = base_address + self.index.load(Ordering::SeqCst);
+    /// right before the red zone in front of an allocation
+    pub struct Record {
+        // the free list overwrites these first, the magic outlives them
+        prev: *mut Record,
+        next: *mut Record,
+        size: usize,
+        callers: [usize; CALLERS],
+        magic: u64,
+    }
+
+    /// where the data starts in the block
+    fn data_offset(layout: Layout) -> usize {
+        align_up(
+            size_of::<Record>() + RED_ZONE,
+            layout.align().max(BLOCK_SIZE),
+        )
+    }
+
+    /// the block for the data of `layout`, its record and its red zones
+    pub fn block_layout(layout: Layout) -> Layout {
+        let size = data_offset(layout) + layout.size() + RED_ZONE;
+        Layout::from_size_align(size, layout.align().max(BLOCK_SIZE)).unwrap()
+    }
 
-        // Align the current index
-        let aligned_address = (current_address + align - 1) & !(align - 1);
+    unsafe fn record(data: *mut u8) -> *mut Record {
+        data.sub(RED_ZONE + size_of::<Record>()) as *mut Record
+    }
 
-        // Check if we have enough space
-        if aligned_address + size > base_address + MEMORY.len() {
-            return core::ptr::null_mut();
+    /// the return addresses up the stack from the caller of `alloc`
+    #[inline(never)]
+    pub fn callers() -> [usize; CALLERS] {
+        let mut callers = [0; CALLERS];
+        let mut frame: usize;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) frame) };
+        // the frame of `callers` returns into `alloc`, the next one into its caller
+        for index in 0..CALLERS + 1 {
+            if frame == 0 || frame & 0x7 != 0 {
+                break;
+            }
+            let (next, return_address) =
+                unsafe { (*(frame as *const usize), *(frame as *const usize).add(1)) };
+            if index > 0 {
+                callers[index - 1] = return_address;
+            }
+            // the stack grows down, the frames of the callers are above
+            if next <= frame {
+                break;
+            }
+            frame = next;
         }
+        callers
+    }
 
-        // Update the index atomically
-        self.index
-            .store(aligned_address - base_address + size, Ordering::SeqCst);
+    /// sets up the record and the red zones of a new block, returns the data
+    pub unsafe fn track(
+        live: &mut *mut Record,
+        block: *mut u8,
+        layout: Layout,
+        callers: [usize; CALLERS],
+    ) -> *mut u8 {
+        let data = block.add(data_offset(layout));
+        data.sub(RED_ZONE).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        data.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        data.write_bytes(ALLOCATED_BYTE, layout.size());
 
-        aligned_address as *mut u8
+        let record = record(data);
+        record.write(Record {
+            prev: null_mut(),
+            next: *live,
+            size: layout.size(),
+            callers,
+            magic: LIVE,
+        });
+        if !live.is_null() {
+            (**live).prev = record;
+        }
+        *live = record;
+        data
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // TODO
+    /// panics on a double free, a wrong layout or a red zone written over
+    pub unsafe fn check(data: *mut u8, layout: Layout) {
+        let record = &*record(data);
+        match record.magic {
+            LIVE => {}
+            FREED => panic!("heap: double free of {:p} ({} bytes)", data, layout.size()),
+            _ => panic!("heap: freeing {:p}, which was not allocated", data),
+        }
+        assert_eq!(
+            record.size,
+            layout.size(),
+            "heap: freeing {:p} with the wrong size, allocated from {:x?}",
+            data,
+            record.callers
+        );
+        let before = core::slice::from_raw_parts(data.sub(RED_ZONE), RED_ZONE);
+        let after = core::slice::from_raw_parts(data.add(layout.size()), RED_ZONE);
+        for (zone, side) in [(before, "before"), (after, "after")] {
+            assert!(
+                zone.iter().all(|&byte| byte == RED_ZONE_BYTE),
+                "heap: red zone {} {:p} ({} bytes) overwritten, allocated from {:x?}",
+                side,
+                data,
+                layout.size(),
+                record.callers
+            );
+        }
+    }
+
+    /// unlinks and poisons a checked allocation, returns its block
+    pub unsafe fn untrack(live: &mut *mut Record, data: *mut u8, layout: Layout) -> *mut u8 {
+        let record = record(data);
+        let Record { prev, next, .. } = *record;
+        if prev.is_null() {
+            *live = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*record).magic = FREED;
+        data.sub(RED_ZONE)
+            .write_bytes(FREED_BYTE, RED_ZONE + layout.size() + RED_ZONE);
+        data.sub(data_offset(layout))
+    }
+
+    pub unsafe fn dump(live: *mut Record) {
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut record = live;
+        while !record.is_null() {
+            let data = (record as *mut u8).add(size_of::<Record>() + RED_ZONE);
+            sprintln!(
+                "{:p}: {} bytes, from {:x?}",
+                data,
+                (*record).size,
+                (*record).callers
+            );
+            count += 1;
+            bytes += (*record).size;
+            record = (*record).next;
+        }
+        sprintln!("heap: {} live allocations, {} bytes", count, bytes);
     }
 }
 
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator::new();
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = stats();
+    panic!(
+        "out of memory allocating {} bytes aligned on {}: heap of {} KiB, {} KiB used, at most {} KiB, {} frames free",
+        layout.size(),
+        layout.align(),
+        stats.size / KB,
+        stats.used / KB,
+        stats.max_size / KB,
+        frames::free_frames()
+    );
+}
@@ -1,5 +1,14 @@
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
+pub mod exceptions;
+pub mod frames;
 pub mod gdt;
 pub mod idt;
+pub mod interrupts;
 pub mod kshell;
 pub mod memory;
+pub mod paging;
 pub mod ports;
+pub mod regions;
+pub mod slab;
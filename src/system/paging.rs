@@ -0,0 +1,451 @@
+//! Access to the page tables, through the mapping of the whole physical memory
+//! the bootloader sets up at `physical_memory_offset`.
+//!
+//! `map`, `unmap`, `protect` and `translate` work on the kernel tables, with the frames
+//! of `frames`. The processes get level 4 tables of their own, see `AddressSpace`, and
+//! the kernel allocates its own virtual ranges from `regions`.
+//!
+//! The parent tables are only user accessible in `process_region`, above the
+//! `user_text` section, and in the ring 3 entry of `claim_level_4_entry`, which the
+//! processes don't have.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::page_table::{PageTableEntry, PageTableIndex};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::frames::{self, GlobalFrameAllocator};
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// physical address of the level 4 table set up by the bootloader
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+// start of the 512 GiB the processes map privately, see `process_region`
+static PROCESS_REGION: AtomicU64 = AtomicU64::new(0);
+// index of the level 4 entry left out of the processes, 0 until claimed
+static KERNEL_ONLY_ENTRY: AtomicU64 = AtomicU64::new(0);
+
+const PAGE_SIZE: usize = 4096;
+
+// taken by `map`, `unmap` and `protect`, with the interrupts disabled
+static TABLES: Mutex<()> = Mutex::new(());
+// physical address of the frame of zeros behind the pages only read so far, 0 until used
+static ZERO_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// marks a read-only entry whose page is writable once copied, see `AddressSpace::copy_on_write`
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+pub fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    // the first level 4 entry of the lower half the bootloader left unused
+    let level_4 = unsafe { table_at(level_4_frame.start_address()) };
+    let index = (1..256)
+        .find(|&index| level_4[index].is_unused())
+        .expect("no free level 4 entry for the processes");
+    let region = Page::from_page_table_indices(
+        PageTableIndex::new(index as u16),
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+    );
+    PROCESS_REGION.store(region.start_address().as_u64(), Ordering::Relaxed);
+}
+
+/// the 512 GiB where each process maps its own memory, unused by the kernel
+pub fn process_region() -> VirtAddr {
+    VirtAddr::new(PROCESS_REGION.load(Ordering::Relaxed))
+}
+
+/// gives an unused level 4 entry of the lower half an empty level 3 table,
+/// returns the start of the 512 GiB it maps
+///
+/// the address spaces created afterwards share the table, and so what it maps, unless
+/// it is for ring 3: the memory of the ring 3 threads outside of the processes is only
+/// in the kernel tables, where no process can reach it. There is one such entry at most
+///
+/// safety: must be called after `frames::init`, before any `AddressSpace` is created
+pub unsafe fn claim_level_4_entry(user: bool) -> VirtAddr {
+    let level_4 = table_at(kernel_level_4().start_address());
+    let process_index = process_region().p4_index();
+    let index = (1..256)
+        .map(|index| PageTableIndex::new(index as u16))
+        .find(|&index| index != process_index && level_4[index].is_unused())
+        .expect("no free level 4 entry left");
+    let frame = frames::allocate().expect("out of memory");
+    table_at(frame.start_address()).zero();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if user {
+        let claimed = KERNEL_ONLY_ENTRY.swap(u16::from(index) as u64, Ordering::Relaxed);
+        assert_eq!(
+            claimed, 0,
+            "claim_level_4_entry: ring 3 entry claimed already"
+        );
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    level_4[index].set_frame(frame, flags);
+    Page::from_page_table_indices(
+        index,
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+        PageTableIndex::new(0),
+    )
+    .start_address()
+}
+
+/// the virtual address where the physical address `addr` can be accessed
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+unsafe fn table_at(addr: PhysAddr) -> &'static mut PageTable {
+    &mut *phys_to_virt(addr).as_mut_ptr()
+}
+
+/// the level 1 entry of `page` in the active tables,
+/// None if a higher level is not present or maps a huge page
+///
+/// `parent_flags` are added to the entries of the higher levels on the way
+unsafe fn level_1_entry(
+    page: Page,
+    parent_flags: PageTableFlags,
+) -> Option<&'static mut PageTableEntry> {
+    let (level_4_frame, _) = Cr3::read();
+    let mut table = table_at(level_4_frame.start_address());
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table[index].set_flags(flags | parent_flags);
+        table = table_at(table[index].addr());
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+/// the kernel tables, to be used with `TABLES` locked
+///
+/// outside of `process_region`, a process shares the tables of the kernel, or doesn't
+/// have the memory at all: changing the kernel tables works whichever are active
+unsafe fn kernel_tables() -> OffsetPageTable<'static> {
+    OffsetPageTable::new(
+        table_at(kernel_level_4().start_address()),
+        VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)),
+    )
+}
+
+fn with_kernel_tables<T>(f: impl FnOnce(&mut OffsetPageTable) -> T) -> T {
+    without_interrupts(|| {
+        let _tables = TABLES.lock();
+        f(&mut unsafe { kernel_tables() })
+    })
+}
+
+/// maps `page` to `frame` in the kernel tables, the missing tables come from `frames`
+///
+/// the tables on the way get the `WRITABLE` and `USER_ACCESSIBLE` of `flags` on top of
+/// theirs, so that they allow what the page does and no more
+///
+/// safety: `frame` must be unused, or meant to be shared, and `page` must not be in use
+pub unsafe fn map(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let parent_flags = PageTableFlags::PRESENT
+        | (flags & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE));
+    with_kernel_tables(|tables| {
+        tables
+            .map_to_with_table_flags(
+                page,
+                frame,
+                flags | PageTableFlags::PRESENT,
+                parent_flags,
+                &mut GlobalFrameAllocator,
+            )
+            .map(|flush| flush.flush())
+    })
+}
+
+/// unmaps `page` from the kernel tables, returns the frame it was mapped to,
+/// which the caller may give back to `frames`
+///
+/// safety: nothing may use the page anymore
+pub unsafe fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
+    with_kernel_tables(|tables| {
+        let (frame, flush) = tables.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// replaces the flags of the mapped `page` in the kernel tables
+///
+/// safety: nothing may rely on the access the page loses
+pub unsafe fn protect(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    with_kernel_tables(|tables| {
+        tables
+            .update_flags(page, flags | PageTableFlags::PRESENT)
+            .map(|flush| flush.flush())
+    })
+}
+
+/// the physical address `addr` is mapped to in the kernel tables
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with_kernel_tables(|tables| tables.translate_addr(addr))
+}
+
+/// whether `addr` is mapped in the active tables
+///
+/// unlike `translate`, it doesn't take `TABLES`: the panic handler may have interrupted
+/// a change to the tables
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let page: Page = Page::containing_address(addr);
+    let (level_4_frame, _) = Cr3::read();
+    let mut table: &PageTable = unsafe { table_at(level_4_frame.start_address()) };
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table = unsafe { table_at(table[index].addr()) };
+    }
+    table[page.p1_index()]
+        .flags()
+        .contains(PageTableFlags::PRESENT)
+}
+
+/// lets ring 3 access a mapped page, or not
+///
+/// the higher levels are made user accessible for good, whether a page is
+/// accessible is decided by its level 1 entry alone
+///
+/// safety: the page must not hold anything the kernel relies on while user accessible
+pub unsafe fn set_user_accessible(page: Page, user: bool) {
+    let entry = level_1_entry(page, PageTableFlags::USER_ACCESSIBLE)
+        .expect("set_user_accessible: page not mapped with 4 KiB pages");
+    let mut flags = entry.flags();
+    flags.set(PageTableFlags::USER_ACCESSIBLE, user);
+    entry.set_flags(flags);
+    tlb::flush(page.start_address());
+}
+
+/// the mappings of a process: those of the kernel, shared, plus its own in `process_region`
+///
+/// the level 4 entries are copied when it is created, those the kernel adds later are
+/// not seen by the process, nor is the ring 3 entry of `claim_level_4_entry`
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    // every frame allocated for it, tables included
+    frames: Vec<PhysFrame>,
+}
+
+impl AddressSpace {
+    /// None if out of memory
+    pub fn new() -> Option<Self> {
+        let mut frames = Vec::new();
+        let (table, level_4) = alloc_page(&mut frames)?;
+        let kernel = unsafe { table_at(kernel_level_4().start_address()) };
+        let table = unsafe { &mut *(table as *mut PageTable) };
+        let kernel_only = KERNEL_ONLY_ENTRY.load(Ordering::Relaxed) as usize;
+        for (index, entry) in kernel.iter().enumerate() {
+            if index != kernel_only || kernel_only == 0 {
+                table[index] = entry.clone();
+            }
+        }
+        Some(Self {
+            level_4: PhysFrame::containing_address(level_4),
+            frames,
+        })
+    }
+
+    /// maps a zeroed page at `page` with `flags`, returns the address the kernel sees it at,
+    /// None if out of memory
+    ///
+    /// the tables on the way are user accessible, as with `set_user_accessible`
+    ///
+    /// safety: `page` must be in `process_region` and not mapped yet
+    pub unsafe fn map_zeroed(&mut self, page: Page, flags: PageTableFlags) -> Option<*mut u8> {
+        let entry = self.new_level_1_entry(page)?;
+        assert!(
+            (*entry).is_unused(),
+            "map_zeroed: {:?} already mapped",
+            page
+        );
+        let (addr, frame) = alloc_page(&mut self.frames)?;
+        (*entry).set_addr(frame, flags | PageTableFlags::PRESENT);
+        Some(addr)
+    }
+
+    /// maps `page` read-only to the frame of zeros, copied on the first write if `flags`
+    /// are writable, None if out of memory
+    ///
+    /// safety: same as `map_zeroed`
+    pub unsafe fn map_zero_page(&mut self, page: Page, flags: PageTableFlags) -> Option<()> {
+        let zero_frame = zero_frame()?;
+        let entry = self.new_level_1_entry(page)?;
+        assert!(
+            (*entry).is_unused(),
+            "map_zero_page: {:?} already mapped",
+            page
+        );
+        let mut flags = flags | PageTableFlags::PRESENT;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+        }
+        (*entry).set_frame(zero_frame, flags);
+        Some(())
+    }
+
+    /// gives the copy-on-write `page` a frame of its own with a copy of its content,
+    /// and makes it writable, false if it is not copy-on-write or out of memory
+    ///
+    /// the frame it shared is left to its other users
+    ///
+    /// safety: the space must be active, the TLB entry of the page is flushed
+    pub unsafe fn copy_on_write(&mut self, page: Page) -> bool {
+        let entry = match self.level_1_entry(page) {
+            Some(entry) if (*entry).flags().contains(COPY_ON_WRITE) => entry,
+            _ => return false,
+        };
+        let (copy, frame) = match alloc_page(&mut self.frames) {
+            Some(page) => page,
+            None => return false,
+        };
+        let shared: *const u8 = phys_to_virt((*entry).addr()).as_ptr();
+        core::ptr::copy_nonoverlapping(shared, copy, PAGE_SIZE);
+        let mut flags = (*entry).flags();
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+        (*entry).set_addr(frame, flags);
+        tlb::flush(page.start_address());
+        true
+    }
+
+    /// the address the kernel sees the mapped `page` at
+    pub fn kernel_addr(&self, page: Page) -> Option<*mut u8> {
+        let entry = unsafe { &*self.level_1_entry(page)? };
+        Some(phys_to_virt(entry.addr()).as_mut_ptr())
+    }
+
+    /// adds `flags` to the entry of the mapped `page`
+    ///
+    /// safety: the process must not rely on the page being read-only
+    pub unsafe fn add_flags(&mut self, page: Page, flags: PageTableFlags) {
+        let entry = &mut *self
+            .level_1_entry(page)
+            .expect("add_flags: page not mapped");
+        entry.set_flags(entry.flags() | flags);
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// the level 1 entry of `page`, the missing tables are allocated on the way,
+    /// None if out of memory
+    ///
+    /// the tables are user accessible, as with `set_user_accessible`
+    unsafe fn new_level_1_entry(&mut self, page: Page) -> Option<*mut PageTableEntry> {
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut table = table_at(self.level_4.start_address());
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            if table[index].is_unused() {
+                let (_, frame) = alloc_page(&mut self.frames)?;
+                table[index].set_addr(frame, parent_flags);
+            }
+            table = table_at(table[index].addr());
+        }
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// None if `page` is not mapped
+    fn level_1_entry(&self, page: Page) -> Option<*mut PageTableEntry> {
+        let mut table = unsafe { table_at(self.level_4.start_address()) };
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            if table[index].is_unused() {
+                return None;
+            }
+            table = unsafe { table_at(table[index].addr()) };
+        }
+        let entry = &mut table[page.p1_index()];
+        if entry.is_unused() {
+            return None;
+        }
+        Some(entry)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            unsafe { frames::deallocate(frame) };
+        }
+    }
+}
+
+fn kernel_level_4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed)))
+}
+
+/// loads the tables of `space`, or those of the kernel, unless they are active already
+///
+/// safety: the code and the stack in use must be mapped in the new tables
+pub unsafe fn activate(space: Option<&AddressSpace>) {
+    let level_4 = space.map_or(kernel_level_4(), AddressSpace::level_4_frame);
+    let (active, flags) = Cr3::read();
+    if active != level_4 {
+        Cr3::write(level_4, flags);
+    }
+}
+
+/// the frame of zeros shared by `map_zero_page`, allocated on first use, never freed
+fn zero_frame() -> Option<PhysFrame> {
+    let addr = ZERO_FRAME.load(Ordering::Relaxed);
+    if addr != 0 {
+        return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+    }
+    let frame = frames::allocate()?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE)
+    };
+    match ZERO_FRAME.compare_exchange(
+        0,
+        frame.start_address().as_u64(),
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    ) {
+        Ok(_) => Some(frame),
+        // another thread got there first
+        Err(addr) => {
+            unsafe { frames::deallocate(frame) };
+            Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+        }
+    }
+}
+
+/// a zeroed frame, recorded in `frames`, returns the address the kernel sees it at
+/// and its physical address
+fn alloc_page(frames: &mut Vec<PhysFrame>) -> Option<(*mut u8, PhysAddr)> {
+    let frame = frames::allocate()?;
+    frames.push(frame);
+    let page: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { page.write_bytes(0, PAGE_SIZE) };
+    Some((page, frame.start_address()))
+}
@@ -0,0 +1,145 @@
+//! Kernel virtual address ranges.
+//!
+//! The kernel region is a level 4 entry of its own, claimed before any process exists,
+//! so every address space sees what is mapped there. The user stacks region holds the
+//! ring 3 stacks of the threads outside of the processes, in an entry no process has.
+//! Ranges of pages are handed out first fit from a sorted list of the free ones,
+//! merged again when given back. They come unmapped, see `paging::map`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::Page;
+
+use super::paging;
+
+/// 512 GiB
+const REGION_PAGES: u64 = 512 * 512 * 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// seen by every address space
+    Kernel,
+    /// seen by the kernel and the ring 3 threads outside of the processes only
+    UserStacks,
+}
+
+// (first page, number of pages) of the free ranges of each region, in order
+static FREE: [Mutex<Vec<(Page, u64)>>; 2] = [Mutex::new(Vec::new()), Mutex::new(Vec::new())];
+
+impl Region {
+    fn free_ranges(self) -> &'static Mutex<Vec<(Page, u64)>> {
+        &FREE[self as usize]
+    }
+}
+
+/// safety: must be called once, after `frames::init`, before any process is created
+pub unsafe fn init() {
+    for (region, user) in [(Region::Kernel, false), (Region::UserStacks, true)] {
+        let start = Page::containing_address(paging::claim_level_4_entry(user));
+        without_interrupts(|| *region.free_ranges().lock() = vec![(start, REGION_PAGES)]);
+    }
+}
+
+/// reserves `pages` contiguous pages of `region`, returns the first one,
+/// None if no range is large enough
+pub fn allocate(region: Region, pages: u64) -> Option<Page> {
+    assert!(pages > 0, "allocate: empty range");
+    without_interrupts(|| {
+        let mut free = region.free_ranges().lock();
+        let index = free.iter().position(|&(_, len)| len >= pages)?;
+        let (start, len) = free[index];
+        if len == pages {
+            free.remove(index);
+        } else {
+            free[index] = (start + pages, len - pages);
+        }
+        Some(start)
+    })
+}
+
+/// gives back a range from `allocate` with the same `region`
+///
+/// safety: the pages must be unmapped, and nothing may use them anymore
+pub unsafe fn free(region: Region, start: Page, pages: u64) {
+    without_interrupts(|| {
+        let mut free = region.free_ranges().lock();
+        let index = free.partition_point(|&(other, _)| other < start);
+        let overlaps_next = free
+            .get(index)
+            .is_some_and(|&(next, _)| start + pages > next);
+        let overlaps_prev = index > 0 && {
+            let (prev, len) = free[index - 1];
+            prev + len > start
+        };
+        assert!(
+            !overlaps_next && !overlaps_prev,
+            "free: {:?} is free already",
+            start
+        );
+
+        free.insert(index, (start, pages));
+        // with the next range, then with the previous one
+        if index + 1 < free.len() && free[index].0 + free[index].1 == free[index + 1].0 {
+            free[index].1 += free.remove(index + 1).1;
+        }
+        if index > 0 && free[index - 1].0 + free[index - 1].1 == free[index].0 {
+            free[index - 1].1 += free.remove(index).1;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::frames;
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    #[test_case]
+    fn test_ranges_are_distinct() {
+        let first = allocate(Region::Kernel, 4).unwrap();
+        let second = allocate(Region::Kernel, 2).unwrap();
+        assert!(second >= first + 4 || second + 2 <= first);
+        unsafe {
+            free(Region::Kernel, first, 4);
+            free(Region::Kernel, second, 2);
+        }
+    }
+
+    #[test_case]
+    fn test_freed_ranges_merge() {
+        let first = allocate(Region::Kernel, 3).unwrap();
+        let second = allocate(Region::Kernel, 5).unwrap();
+        assert_eq!(second, first + 3);
+        unsafe {
+            free(Region::Kernel, first, 3);
+            free(Region::Kernel, second, 5);
+        }
+        // unmerged, the first range would be too small
+        assert_eq!(allocate(Region::Kernel, 8), Some(first));
+        unsafe { free(Region::Kernel, first, 8) };
+    }
+
+    #[test_case]
+    fn test_map_translate_protect_unmap() {
+        let page = allocate(Region::Kernel, 1).unwrap();
+        let frame = frames::allocate().unwrap();
+        let addr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe {
+            paging::map(page, frame, PageTableFlags::WRITABLE).unwrap();
+            addr.write(42);
+            assert_eq!(
+                paging::translate(page.start_address() + 8u64),
+                Some(frame.start_address() + 8u64)
+            );
+            paging::protect(page, PageTableFlags::empty()).unwrap();
+            assert_eq!(addr.read(), 42);
+            assert_eq!(paging::unmap(page).unwrap(), frame);
+            frames::deallocate(frame);
+            free(Region::Kernel, page, 1);
+        }
+        assert_eq!(paging::translate(VirtAddr::from_ptr(addr)), None);
+    }
+}
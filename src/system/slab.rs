@@ -0,0 +1,317 @@
+//! Object caches, for the kernel objects allocated and freed often.
+//!
+//! A `KmemCache<T>` carves slabs taken from the heap into slots for a `T`. A slab is
+//! aligned on its size, with its header at the start, so the slab of an object is
+//! found by rounding its address down. The free slots of a slab are linked together,
+//! and a slab left empty goes back to the heap, unless it is the only empty one.
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const PAGE_SIZE: usize = 4096;
+/// a slab is large enough for at least that many objects
+const MIN_SLOTS: usize = 8;
+
+// every cache that has allocated, for `all_stats`
+static CACHES: Mutex<Vec<&'static dyn Cache>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// the size of a slot, at least that of the object
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    /// slots in all the slabs
+    pub capacity: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+pub trait Cache: Sync {
+    fn stats(&self) -> CacheStats;
+}
+
+/// the statistics of the caches in use
+pub fn all_stats() -> Vec<CacheStats> {
+    let caches = without_interrupts(|| CACHES.lock().clone());
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+/// a cache of slots for `T`, meant to be a static
+pub struct KmemCache<T> {
+    name: &'static str,
+    slabs: Mutex<Slabs>,
+    registered: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+// the objects are only reached through their `CacheBox`
+unsafe impl<T: Send> Sync for KmemCache<T> {}
+
+struct Slabs {
+    slabs: Vec<*mut SlabHeader>,
+    allocs: usize,
+    frees: usize,
+}
+
+unsafe impl Send for Slabs {}
+
+struct SlabHeader {
+    free: *mut FreeSlot,
+    in_use: usize,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+/// the geometry of the slabs of a cache
+#[derive(Clone, Copy)]
+struct SlabLayout {
+    size: usize,
+    first_slot: usize,
+    slot_size: usize,
+    slots: usize,
+}
+
+impl SlabLayout {
+    const fn new(object_size: usize, object_align: usize) -> Self {
+        let slot_align = max(object_align, align_of::<FreeSlot>());
+        let slot_size = align_up(max(object_size, size_of::<FreeSlot>()), slot_align);
+        let first_slot = align_up(size_of::<SlabHeader>(), slot_align);
+        let mut size = PAGE_SIZE;
+        while size < first_slot + MIN_SLOTS * slot_size {
+            size *= 2;
+        }
+        Self {
+            size,
+            first_slot,
+            slot_size,
+            slots: (size - first_slot) / slot_size,
+        }
+    }
+
+    fn heap_layout(&self) -> Layout {
+        Layout::from_size_align(self.size, self.size).unwrap()
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl Slabs {
+    /// a free slot, from a new slab if none is left, None if out of memory
+    unsafe fn alloc(&mut self, layout: SlabLayout) -> Option<*mut u8> {
+        let slab = match self.slabs.iter().find(|&&slab| !(*slab).free.is_null()) {
+            Some(&slab) => slab,
+            None => {
+                let slab = new_slab(layout)?;
+                self.slabs.push(slab);
+                slab
+            }
+        };
+        let slot = (*slab).free;
+        (*slab).free = (*slot).next;
+        (*slab).in_use += 1;
+        self.allocs += 1;
+        Some(slot as *mut u8)
+    }
+
+    unsafe fn free(&mut self, slot: *mut u8, layout: SlabLayout) {
+        let slab = (slot as usize & !(layout.size - 1)) as *mut SlabHeader;
+        let slot = slot as *mut FreeSlot;
+        slot.write(FreeSlot { next: (*slab).free });
+        (*slab).free = slot;
+        (*slab).in_use -= 1;
+        self.frees += 1;
+
+        let empty = self
+            .slabs
+            .iter()
+            .filter(|&&slab| (*slab).in_use == 0)
+            .count();
+        if (*slab).in_use == 0 && empty > 1 {
+            self.slabs.retain(|&other| other != slab);
+            dealloc(slab as *mut u8, layout.heap_layout());
+        }
+    }
+}
+
+/// a slab from the heap, all its slots free
+unsafe fn new_slab(layout: SlabLayout) -> Option<*mut SlabHeader> {
+    let base = alloc(layout.heap_layout());
+    if base.is_null() {
+        return None;
+    }
+    let mut free = null_mut();
+    for index in (0..layout.slots).rev() {
+        let slot = base.add(layout.first_slot + index * layout.slot_size) as *mut FreeSlot;
+        slot.write(FreeSlot { next: free });
+        free = slot;
+    }
+    let slab = base as *mut SlabHeader;
+    slab.write(SlabHeader { free, in_use: 0 });
+    Some(slab)
+}
+
+impl<T: Send + 'static> KmemCache<T> {
+    const LAYOUT: SlabLayout = SlabLayout::new(size_of::<T>(), align_of::<T>());
+
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            slabs: Mutex::new(Slabs {
+                slabs: Vec::new(),
+                allocs: 0,
+                frees: 0,
+            }),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// moves `value` in a slot of the cache, gives it back if out of memory
+    pub fn alloc(&'static self, value: T) -> Result<CacheBox<T>, T> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            without_interrupts(|| CACHES.lock().push(self));
+        }
+        let slot = without_interrupts(|| unsafe { self.slabs.lock().alloc(Self::LAYOUT) });
+        match slot {
+            Some(slot) => {
+                let ptr = slot as *mut T;
+                unsafe { ptr.write(value) };
+                Ok(CacheBox {
+                    ptr: NonNull::new(ptr).unwrap(),
+                    cache: self,
+                })
+            }
+            None => Err(value),
+        }
+    }
+
+    /// safety: `ptr` must come from `alloc`, its object dropped already
+    unsafe fn free(&self, ptr: *mut T) {
+        without_interrupts(|| self.slabs.lock().free(ptr as *mut u8, Self::LAYOUT));
+    }
+}
+
+impl<T: Send + 'static> Cache for KmemCache<T> {
+    fn stats(&self) -> CacheStats {
+        let slabs = without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            let in_use = slabs
+                .slabs
+                .iter()
+                .map(|&slab| unsafe { (*slab).in_use })
+                .sum();
+            (slabs.slabs.len(), in_use, slabs.allocs, slabs.frees)
+        });
+        CacheStats {
+            name: self.name,
+            object_size: Self::LAYOUT.slot_size,
+            slab_size: Self::LAYOUT.size,
+            slabs: slabs.0,
+            in_use: slabs.1,
+            capacity: slabs.0 * Self::LAYOUT.slots,
+            allocs: slabs.2,
+            frees: slabs.3,
+        }
+    }
+}
+
+/// an object in a `KmemCache`, like a `Box`: dropping it drops the object
+/// and frees its slot
+pub struct CacheBox<T: Send + 'static> {
+    ptr: NonNull<T>,
+    cache: &'static KmemCache<T>,
+}
+
+unsafe impl<T: Send + 'static> Send for CacheBox<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for CacheBox<T> {}
+
+impl<T: Send + 'static> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Send + 'static> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: Send + 'static> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.cache.free(self.ptr.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NUMBERS: KmemCache<usize> = KmemCache::new("test numbers");
+
+    #[test_case]
+    fn test_objects_are_distinct() {
+        let objects: Vec<CacheBox<usize>> = (0..1000).map(|i| NUMBERS.alloc(i).unwrap()).collect();
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(**object, i);
+        }
+        let stats = NUMBERS.stats();
+        assert_eq!(stats.in_use, 1000);
+        assert!(stats.slabs > 1 && stats.capacity >= 1000);
+    }
+
+    #[test_case]
+    fn test_empty_slabs_are_freed() {
+        let objects: Vec<CacheBox<usize>> = (0..1000).map(|i| NUMBERS.alloc(i).unwrap()).collect();
+        let allocs = NUMBERS.stats().allocs;
+        drop(objects);
+        let stats = NUMBERS.stats();
+        assert_eq!(stats.in_use, 0);
+        assert_eq!(stats.slabs, 1);
+        assert_eq!(stats.frees, allocs);
+    }
+
+    #[repr(align(64))]
+    struct Aligned(u8);
+
+    static ALIGNED: KmemCache<Aligned> = KmemCache::new("test aligned");
+
+    #[test_case]
+    fn test_objects_are_aligned() {
+        let objects: Vec<CacheBox<Aligned>> = (0..100)
+            .map(|i| ALIGNED.alloc(Aligned(i)).ok().unwrap())
+            .collect();
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(&**object as *const Aligned as usize % 64, 0);
+            assert_eq!(object.0, i as u8);
+        }
+        assert!(all_stats().iter().any(|stats| stats.name == "test aligned"));
+    }
+}
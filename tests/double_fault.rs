@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use primoria::{exit_qemu, sprint, sprintln, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
+
+    sprint!("double_fault::double_fault...\t");
+    // the cpu can't push the #UD frame on a non-canonical stack, nor the frame of
+    // that fault, the double fault handler gets a stack of its own
+    unsafe { core::arch::asm!("mov rsp, {}", "ud2", in(reg) 1u64 << 63) };
+
+    sprintln!("[no double fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    sprintln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
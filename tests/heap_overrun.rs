@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use primoria::{exit_qemu, sprint, sprintln, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
+    overrun();
+    sprintln!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// writes one byte past the end of a vec, freeing it must notice
+fn overrun() {
+    sprint!("heap_overrun::overrun...\t");
+    let mut vec: Vec<u8> = Vec::with_capacity(10);
+    unsafe { vec.as_mut_ptr().add(10).write(0) };
+    drop(vec);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    sprintln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use primoria::{exit_qemu, sprint, sprintln, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
+
+    sprint!("machine_check::machine_check...\t");
+    // raised, a real one would come from the hardware
+    unsafe { core::arch::asm!("int 18") };
+
+    sprintln!("[no panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    sprintln!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -6,6 +6,7 @@
 
 extern crate alloc;
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 #[panic_handler]
@@ -13,9 +14,10 @@ fn panic(info: &PanicInfo) -> ! {
     primoria::test_panic_handler(info)
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    primoria::init();
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
 
     test_main();
 
@@ -31,3 +33,93 @@ fn simple_allocation() {
     assert_eq!(*heap_value_1, 41);
     assert_eq!(*heap_value_2, 13);
 }
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use primoria::system::memory;
+
+/// far more than the heap needs to grow to, unless freed memory is not reused
+const TOTAL: usize = 64 * 1024 * 1024;
+
+#[test_case]
+fn many_boxes() {
+    let size = memory::stats().size;
+    for i in 0..TOTAL / 4096 {
+        let value = Box::new([i as u8; 4096]);
+        assert_eq!(value[4095], i as u8);
+    }
+    assert!(memory::stats().size <= size + 1024 * 1024);
+}
+
+#[test_case]
+fn large_vecs() {
+    for round in 0..TOTAL / (1024 * 1024) {
+        let vec: Vec<usize> = (0..1024 * 1024 / 8).map(|i| i + round).collect();
+        assert_eq!(vec[1000], 1000 + round);
+    }
+}
+
+#[test_case]
+fn growing_vec() {
+    let mut vec = Vec::new();
+    for i in 0..256 * 1024 {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<usize>(), (256 * 1024 - 1) * 256 * 1024 / 2);
+}
+
+#[test_case]
+fn long_lived_survives() {
+    let long_lived = Box::new(42);
+    for i in 0..TOTAL / 4096 {
+        let value = Box::new([i as u8; 4096]);
+        assert_eq!(value[0], i as u8);
+    }
+    assert_eq!(*long_lived, 42);
+}
+
+#[test_case]
+fn mixed_sizes_and_alignments() {
+    for round in 0..1000 {
+        let layouts: Vec<Layout> = (0..16)
+            .map(|i| Layout::from_size_align(1 + (i * 37 + round) % 3000, 1 << (i % 13)).unwrap())
+            .collect();
+        let blocks: Vec<*mut u8> = layouts
+            .iter()
+            .map(|&layout| {
+                let block = unsafe { alloc(layout) };
+                assert!(!block.is_null());
+                assert_eq!(block as usize % layout.align(), 0);
+                unsafe { block.write_bytes(round as u8, layout.size()) };
+                block
+            })
+            .collect();
+        // every other one first, so that the others are merged with their neighbours
+        for index in (0..16).step_by(2).chain((1..16).step_by(2)) {
+            assert_eq!(unsafe { *blocks[index] }, round as u8);
+            unsafe { dealloc(blocks[index], layouts[index]) };
+        }
+    }
+}
+
+#[test_case]
+fn large_block_after_fragmentation() {
+    // fragments the heap then frees everything, the blocks must merge again
+    let small: Vec<Box<[u8; 100]>> = (0..10_000).map(|_| Box::new([0; 100])).collect();
+    let size = memory::stats().size;
+    drop(small);
+    let large = Vec::<u8>::with_capacity(size / 2);
+    assert_eq!(memory::stats().size, size);
+    drop(large);
+}
+
+#[test_case]
+fn heap_grows() {
+    let used = memory::stats().used;
+    let mut large = Vec::<u8>::with_capacity(32 * 1024 * 1024);
+    large.resize(large.capacity(), 42);
+    assert_eq!(large[32 * 1024 * 1024 - 1], 42);
+    assert!(memory::stats().size >= 32 * 1024 * 1024);
+    drop(large);
+    assert_eq!(memory::stats().used, used);
+}
@@ -10,16 +10,31 @@ fn panic(info: &PanicInfo) -> ! {
     primoria::test_panic_handler(info)
 }
 
+use bootloader::{entry_point, BootInfo};
+use primoria::kernel::{join, launch, STACK_OVERFLOW_EXIT_CODE};
 use primoria::sprint;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    primoria::init(boot_info);
+
+    sprint!("stack_overflow::thread_stack_overflow...\t");
+    // the thread hits the guard page below its stack and gets killed
+    let id = launch(stack_overflow).unwrap();
+    assert_eq!(join(id), STACK_OVERFLOW_EXIT_CODE);
+    // the others keep running, and the stack can be given to a new thread
+    let id = launch(|| {}).unwrap();
+    assert_eq!(join(id), 0);
+    sprintln!("[ok]");
+
     sprint!("stack_overflow::stack_overflow...\t");
 
-    primoria::system::gdt::init();
+    // no more timer interrupts, the test IDT does not handle them
+    x86_64::instructions::interrupts::disable();
     init_test_idt();
 
-    // trigger a stack overflow
+    // trigger a stack overflow on the boot stack
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
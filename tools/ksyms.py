@@ -0,0 +1,110 @@
+#!/usr/bin/env python3
+"""Writes the symbol table of a linked kernel into its .ksyms section.
+
+usage: ksyms.py KERNEL_ELF
+
+The functions of the ELF symbol table, demangled and sorted by address, in the
+format `system::backtrace` reads. Only the content of the section changes, so the
+addresses stay valid. If the table doesn't fit, the section is left empty.
+"""
+
+import re
+import struct
+import subprocess
+import sys
+
+MAGIC = b"KSYM"
+SECTION = b".ksyms"
+MAX_NAME_LEN = 200
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+
+def demangle(names):
+    """the Rust paths of the symbols, without their hashes
+
+    c++filt, from binutils, knows both Rust manglings; without it the names stay mangled
+    """
+    try:
+        output = subprocess.run(["c++filt"], input="\n".join(names), capture_output=True,
+                                text=True, check=True).stdout.splitlines()
+    except (OSError, subprocess.CalledProcessError):
+        return names
+    if len(output) != len(names):
+        return names
+    return [re.sub(r"\[[0-9a-f]{16}\]|::h[0-9a-f]{16}$", "", name) for name in output]
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = []
+    for index in range(shnum):
+        (name, kind, _flags, _addr, offset, size, link, _info, _align,
+         entsize) = struct.unpack_from("<IIQQQQIIQQ", elf, shoff + index * shentsize)
+        headers.append((name, kind, offset, size, link, entsize))
+    _, _, names_offset, _, _, _ = headers[shstrndx]
+    named = []
+    for name, kind, offset, size, link, entsize in headers:
+        end = elf.index(b"\0", names_offset + name)
+        named.append((elf[names_offset + name:end], kind, offset, size, link, entsize))
+    return named
+
+
+def functions(elf, headers):
+    """(address, size, name) of the functions, by address"""
+    found = {}
+    for _, kind, offset, size, link, entsize in headers:
+        if kind != SHT_SYMTAB:
+            continue
+        strings = headers[link][2]
+        for index in range(size // entsize):
+            name, info, _other, _shndx, value, length = struct.unpack_from(
+                "<IBBHQQ", elf, offset + index * entsize)
+            if info & 0xF != STT_FUNC or value == 0 or length == 0:
+                continue
+            end = elf.index(b"\0", strings + name)
+            found[value] = (length, elf[strings + name:end].decode("utf-8", "replace"))
+    addresses = sorted(found)
+    names = demangle([found[addr][1] for addr in addresses])
+    return [(addr, found[addr][0], name[:MAX_NAME_LEN]) for addr, name in zip(addresses, names)]
+
+
+def table(functions):
+    names = b""
+    entries = b""
+    names_start = len(MAGIC) + 4 + 20 * len(functions)
+    for addr, length, name in functions:
+        encoded = name.encode("utf-8")
+        entries += struct.pack("<QIII", addr, min(length, 0xFFFFFFFF),
+                               names_start + len(names), len(encoded))
+        names += encoded
+    return MAGIC + struct.pack("<I", len(functions)) + entries + names
+
+
+def main(path):
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit(f"ksyms: {path} is not a 64-bit ELF file")
+    headers = sections(elf)
+    section = next((h for h in headers if h[0] == SECTION), None)
+    if section is None:
+        sys.exit(f"ksyms: no {SECTION.decode()} section in {path}")
+    _, _, offset, size, _, _ = section
+
+    content = table(functions(elf, headers))
+    if len(content) > size:
+        print(f"ksyms: {len(content)} bytes of symbols, only {size} reserved, "
+              "see SYMBOLS_SIZE in src/system/backtrace.rs", file=sys.stderr)
+        content = b""
+    elf[offset:offset + size] = content.ljust(size, b"\0")
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    main(sys.argv[1])
@@ -0,0 +1,5 @@
+#!/bin/bash
+# cargo runner: embeds the symbol table in the kernel for the backtraces,
+# see `system::backtrace`, then boots it with bootimage
+python3 "$(dirname "$0")/ksyms.py" "$1" || echo "no symbol table embedded" >&2
+exec bootimage runner "$@"